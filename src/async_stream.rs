@@ -0,0 +1,235 @@
+use std::{error::Error, future::Future, io, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    Envelope, HEADER_LEN, Message, RateLimiter,
+    structs::{Heartbeat, MessageType},
+};
+
+/// Most of a payload read from the connection in one go
+const READ_CHUNK: usize = 2usize.pow(16);
+
+pub trait AsyncConnection: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncConnection for T {}
+
+/// Both ends of a connection driven by an event loop rather than a thread of its own. Frames
+/// messages exactly like [`crate::StreamIterator`], so the peer can't tell the two apart
+pub struct AsyncStream {
+    inner: Box<dyn AsyncConnection>,
+    /// Longest a single read waits on the peer, like a socket's read timeout
+    read_timeout: Option<Duration>,
+}
+
+impl AsyncStream {
+    pub fn new(inner: impl AsyncConnection + 'static) -> Self {
+        Self {
+            inner: Box::new(inner),
+            read_timeout: None,
+        }
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Reads whatever's there, waiting at most the read timeout for anything at all. A peer
+    /// trickling a big message in slowly is fine as long as it keeps going
+    async fn read_some(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf);
+        match self.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, read)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+            None => read.await,
+        }
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read_some(&mut buf[filled..]).await? {
+                0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+                read => filled += read,
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_envelope(&mut self) -> io::Result<Envelope> {
+        let mut header = [0; HEADER_LEN];
+        self.read_exact(&mut header).await?;
+        let (kind, len) = Envelope::parse_header(header)?;
+
+        // Grown as it's read so a bogus length can't make us allocate it all up front
+        let mut payload = Vec::new();
+        let mut chunk = vec![0; READ_CHUNK.min(len as usize)];
+        while (payload.len() as u64) < len {
+            let want = chunk.len().min((len - payload.len() as u64) as usize);
+            match self.read_some(&mut chunk[..want]).await? {
+                0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+                read => payload.extend_from_slice(&chunk[..read]),
+            }
+        }
+        Ok(Envelope { kind, payload })
+    }
+
+    pub async fn send<M: Message>(&mut self, message: M) -> io::Result<()> {
+        self.send_envelope(Envelope::new(message)).await
+    }
+
+    pub async fn send_envelope(&mut self, envelope: Envelope) -> io::Result<()> {
+        self.inner.write_all(&envelope.into_bytes()).await?;
+        // TLS holds on to what's written until it's flushed
+        self.inner.flush().await
+    }
+
+    /// Closes our side and waits up to `linger` for the peer to close theirs, reading away what it
+    /// still sends so closing doesn't reset the connection and lose what we last sent
+    pub async fn hang_up(&mut self, linger: Duration) {
+        let drain = async {
+            if self.inner.shutdown().await.is_err() {
+                return;
+            }
+            let mut buf = vec![0; READ_CHUNK];
+            while let Ok(1..) = self.inner.read(&mut buf).await {}
+        };
+        let _ = tokio::time::timeout(linger, drain).await;
+    }
+
+    /// Reads the next message that isn't a [`Heartbeat`]. Running into the read timeout means
+    /// the peer sent nothing at all for that long, so it's most likely gone
+    pub async fn recv_envelope(&mut self) -> io::Result<Envelope> {
+        loop {
+            let envelope = self.read_envelope().await.map_err(|err| match err.kind() {
+                io::ErrorKind::TimedOut => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Heard nothing from the peer, not even a heartbeat, before the read timeout",
+                ),
+                _ => err,
+            })?;
+            if envelope.message_type() != Some(MessageType::Heartbeat) {
+                return Ok(envelope);
+            }
+        }
+    }
+
+    /// Reads the next message, which has to be an `M`
+    pub async fn recv<M: Message>(&mut self) -> Result<M, Box<dyn Error + Send + Sync>> {
+        self.recv_envelope()
+            .await?
+            .open()
+            .map_err(|err| err.to_string().into())
+    }
+
+    /// Waits on `work`, sending a [`Heartbeat`] every `interval` until it's done so the peer
+    /// doesn't give up on us. A zero `interval` means the peer doesn't understand them
+    pub async fn with_heartbeats<T>(
+        &mut self,
+        interval: Duration,
+        work: impl Future<Output = T>,
+    ) -> io::Result<T> {
+        if interval.is_zero() {
+            return Ok(work.await);
+        }
+
+        let mut work = std::pin::pin!(work);
+        let mut seq = 0;
+        loop {
+            match tokio::time::timeout(interval, &mut work).await {
+                Ok(out) => return Ok(out),
+                Err(_) => self.send(Heartbeat { seq }).await?,
+            }
+            seq += 1;
+        }
+    }
+
+    /// Runs `work` on the runtime's blocking threads, for the database and file I/O that would
+    /// otherwise hold up every other connection, with heartbeats until it's done
+    pub async fn blocking<T: Send + 'static>(
+        &mut self,
+        interval: Duration,
+        work: impl FnOnce() -> T + Send + 'static,
+    ) -> io::Result<T> {
+        self.with_heartbeats(interval, tokio::task::spawn_blocking(work))
+            .await?
+            .map_err(|_| io::Error::other("Work running alongside heartbeats panicked"))
+    }
+
+    /// [`crate::StreamIterator::throttle`] for the event loop
+    pub async fn throttle(
+        &mut self,
+        limiters: &[&RateLimiter],
+        bytes: u64,
+        heartbeat: Duration,
+    ) -> io::Result<()> {
+        let wait = limiters
+            .iter()
+            .map(|limiter| limiter.take(bytes))
+            .max()
+            .unwrap_or_default();
+        match wait.is_zero() {
+            true => Ok(()),
+            false => {
+                self.with_heartbeats(heartbeat, tokio::time::sleep(wait))
+                    .await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        StreamIterator,
+        structs::{Compression, FilePart},
+    };
+
+    fn part(len: usize) -> FilePart {
+        FilePart {
+            offset: 42,
+            checksum: 7,
+            compression: Compression::None,
+            data: (0..len).map(|i| i as u8).collect(),
+        }
+    }
+
+    #[test]
+    fn frames_like_stream_iterator() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Sends back whatever comes in as is, after a heartbeat that should go unnoticed
+        let echo = std::thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut stream = StreamIterator(Box::new(tcp));
+            let envelope = stream.recv_envelope().unwrap();
+            stream.send(Heartbeat { seq: 0 }).unwrap();
+            stream.send_envelope(envelope).unwrap();
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        // Bigger than a single read, so it comes back in pieces
+        let sent = part(3 * READ_CHUNK + 5);
+        let received = runtime.block_on(async {
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut stream = AsyncStream::new(tcp);
+            stream.set_read_timeout(Some(Duration::from_secs(5)));
+            stream.send(sent.clone()).await.unwrap();
+            stream.recv::<FilePart>().await.unwrap()
+        });
+        echo.join().unwrap();
+
+        assert_eq!(
+            (received.offset, received.checksum),
+            (sent.offset, sent.checksum)
+        );
+        assert_eq!(received.compression, sent.compression);
+        assert_eq!(received.data, sent.data);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{sync::Mutex, time::Duration};
+
+/// How long one part should take at the measured throughput. Long enough that the database write
+/// every part costs the server doesn't matter, short enough that a drop throws little away
+const TARGET_PART_TIME: Duration = Duration::from_secs(2);
+/// How much a new throughput measurement counts against the ones before it
+const SMOOTHING: f64 = 0.25;
+/// Parts that have to go through in a row after a loss, or the last change, before growing again
+const GROW_AFTER: u32 = 8;
+/// Sizes are kept to whole multiples of this
+const GRANULARITY: u64 = 2u64.pow(16);
+
+/// The part size to send an upload with. Shared by all its connections and kept across
+/// reconnects, so it remembers how the link has been doing
+#[derive(Debug)]
+pub struct PacketSizer {
+    adaptive: bool,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    size: u64,
+    min: u64,
+    max: u64,
+    /// Bytes a second, smoothed over the parts so far
+    throughput: Option<f64>,
+    /// Parts acknowledged since the last loss or change of size
+    streak: u32,
+}
+
+impl PacketSizer {
+    /// Always `size`, as far as the server allows it
+    pub fn fixed(size: u64) -> Self {
+        Self::new(size, false)
+    }
+
+    /// Starts at `size`, then follows the throughput and losses
+    pub fn adaptive(size: u64) -> Self {
+        Self::new(size, true)
+    }
+
+    fn new(size: u64, adaptive: bool) -> Self {
+        Self {
+            adaptive,
+            state: Mutex::new(State {
+                size,
+                min: size,
+                max: size,
+                throughput: None,
+                streak: 0,
+            }),
+        }
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive
+    }
+
+    /// Keeps the size within the part sizes the server accepts
+    pub fn set_bounds(&self, min: u64, max: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.min = min;
+        state.max = max;
+        state.size = state.size.clamp(min, max);
+    }
+
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    /// A part of `len` bytes was acknowledged `elapsed` after the server could start on it
+    pub fn acknowledged(&self, len: u64, elapsed: Duration) {
+        if !self.adaptive || elapsed.is_zero() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let sample = len as f64 / elapsed.as_secs_f64();
+        let throughput = match state.throughput {
+            Some(throughput) => throughput + SMOOTHING * (sample - throughput),
+            None => sample,
+        };
+        state.throughput = Some(throughput);
+        state.streak += 1;
+
+        // Shrinking is done straight away, growing only once the link has held up for a while
+        let ideal = (throughput * TARGET_PART_TIME.as_secs_f64()) as u64;
+        let size = state.size;
+        let next = match ideal.cmp(&size) {
+            std::cmp::Ordering::Less => ideal.max(size / 2),
+            std::cmp::Ordering::Greater if state.streak >= GROW_AFTER => ideal.min(size * 2),
+            _ => return,
+        };
+        state.resize(next);
+    }
+
+    /// A part came through corrupted or the connection dropped. Halves the size, so a link that
+    /// keeps losing things loses less each time
+    pub fn lost(&self) {
+        if !self.adaptive {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let next = state.size / 2;
+        state.resize(next);
+    }
+}
+
+impl State {
+    fn resize(&mut self, size: u64) {
+        let size = (size - size % GRANULARITY).clamp(self.min, self.max);
+        self.streak = 0;
+        self.size = size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 2u64.pow(20);
+
+    #[test]
+    fn follows_throughput_and_losses() {
+        let sizer = PacketSizer::adaptive(4 * MIB);
+        sizer.set_bounds(MIB, 64 * MIB);
+
+        // 4 MiB a part in 0.25s is 16 MiB/s, so parts of 32 MiB would take the target time
+        for _ in 0..GROW_AFTER - 1 {
+            sizer.acknowledged(4 * MIB, Duration::from_millis(250));
+        }
+        assert_eq!(sizer.size(), 4 * MIB);
+        sizer.acknowledged(4 * MIB, Duration::from_millis(250));
+        assert_eq!(sizer.size(), 8 * MIB);
+
+        sizer.lost();
+        assert_eq!(sizer.size(), 4 * MIB);
+        for _ in 0..10 {
+            sizer.lost();
+        }
+        assert_eq!(sizer.size(), MIB);
+
+        // A slow part shrinks it right away, but never past the bounds
+        let sizer = PacketSizer::adaptive(8 * MIB);
+        sizer.set_bounds(MIB, 64 * MIB);
+        sizer.acknowledged(8 * MIB, Duration::from_secs(8));
+        assert_eq!(sizer.size(), 4 * MIB);
+        sizer.set_bounds(MIB, 2 * MIB);
+        assert_eq!(sizer.size(), 2 * MIB);
+
+        let fixed = PacketSizer::fixed(4 * MIB);
+        fixed.set_bounds(MIB, 64 * MIB);
+        fixed.acknowledged(4 * MIB, Duration::from_millis(1));
+        fixed.lost();
+        assert_eq!(fixed.size(), 4 * MIB);
+    }
+}
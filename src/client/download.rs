@@ -0,0 +1,234 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fs,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use indicatif::ProgressBar;
+use lazy_marshal::prelude::*;
+use sha2::{Digest, Sha256};
+
+use stable_ftp::{
+    MAX_PART_RETRIES, file_size_text, logger, num_packets,
+    structs::{
+        Compression, DownloadParts, DownloadRequest, DownloadResponse, FileDescription, FilePart,
+        PartRange,
+    },
+    update_hash,
+};
+
+use crate::{Connection, FileResult, progress_bar};
+
+/// How far a download got. Kept next to the file until it's finished so it can be resumed
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+struct DownloadState {
+    hash: Vec<u8>,
+    size: u64,
+    packet_size: u64,
+    /// Parts that passed their checksum and are written to the file
+    received: Vec<PartRange>,
+}
+
+impl DownloadState {
+    fn matches(&self, file_description: &FileDescription) -> bool {
+        self.hash == file_description.hash
+            && self.size == file_description.size
+            && self.packet_size == file_description.packet_size
+    }
+}
+
+fn state_path(dest: &Path) -> PathBuf {
+    let mut path = dest.as_os_str().to_owned();
+    path.push(".stable-ftp");
+    PathBuf::from(path)
+}
+
+fn load_state(dest: &Path) -> Option<DownloadState> {
+    if !dest.exists() {
+        return None;
+    }
+    let bytes = fs::read(state_path(dest)).ok()?;
+    DownloadState::unmarshal(&mut bytes.into_iter()).ok()
+}
+
+fn save_state(dest: &Path, state: &DownloadState) -> std::io::Result<()> {
+    // Written aside then moved over, so dying halfway through can't lose track of what we have
+    let mut tmp = state_path(dest).into_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, state.clone().marshal().collect::<Vec<_>>())?;
+    fs::rename(tmp, state_path(dest))
+}
+
+fn hash_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    update_hash(&mut hasher, fs::File::open(path)?)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Tells the server we don't want any more parts of this file
+fn finish(conn: &mut Connection) -> Result<(), Box<dyn Error>> {
+    Ok(conn.stream.send(DownloadParts { parts: Vec::new() })?)
+}
+
+/// Fetches `name` from the server into `dest`, picking up where an earlier attempt stopped
+pub fn download_file(
+    conn: &mut Connection,
+    name: &str,
+    dest: &Path,
+    packet_size: u64,
+    bar: &mut Option<ProgressBar>,
+) -> Result<FileResult, Box<dyn Error>> {
+    let state = load_state(dest);
+    conn.stream.send(DownloadRequest {
+        name: name.to_string(),
+        // Parts only line up with what we already have if they stay the same size
+        packet_size: state
+            .as_ref()
+            .map_or(packet_size.min(conn.agreed.max_packet_size), |state| {
+                state.packet_size
+            }),
+    })?;
+    let file_description = match conn.stream.recv::<DownloadResponse>()? {
+        DownloadResponse::File(file_description) => file_description,
+        DownloadResponse::FailMessage(message) => return Ok(FileResult::Failed(message)),
+    };
+
+    let mut state = match state {
+        Some(state) if state.matches(&file_description) => {
+            let received: u64 = state.received.iter().map(PartRange::len).sum();
+            logger::info(format!(
+                "Resuming \"{}\" with {received} parts already downloaded",
+                dest.display()
+            ));
+            state
+        }
+        // Never overwrite a file we weren't the ones downloading
+        None if dest.exists() => {
+            finish(conn)?;
+            return Ok(match hash_file(dest)? == file_description.hash {
+                true => FileResult::AlreadyExists,
+                false => FileResult::Failed(format!(
+                    "\"{}\" already exists and doesn't match the file on the server, not overwriting it",
+                    dest.display()
+                )),
+            });
+        }
+        _ => {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = fs::File::create(dest)?;
+            file.set_len(file_description.size)?;
+            DownloadState {
+                hash: file_description.hash.clone(),
+                size: file_description.size,
+                packet_size: file_description.packet_size,
+                received: Vec::new(),
+            }
+        }
+    };
+    save_state(dest, &state)?;
+
+    let packet_size = state.packet_size;
+    let total_packets = num_packets(packet_size, state.size);
+    let mut received = state
+        .received
+        .iter()
+        .flat_map(|range| range.start..range.end)
+        .collect::<BTreeSet<_>>();
+    let mut file = fs::File::options().write(true).open(dest)?;
+    let received_bytes = received
+        .iter()
+        .map(|part_num| packet_size.min(state.size - part_num * packet_size))
+        .sum();
+    let bar = progress_bar(bar, state.size, received_bytes)?;
+    bar.set_message(format!("{}/part", file_size_text(packet_size)));
+
+    // Corrupted parts are skipped and asked for again in the next round
+    let mut retries = 0;
+    loop {
+        let missing = PartRange::complement(&state.received, total_packets);
+        if missing.is_empty() {
+            break;
+        }
+        let expected: u64 = missing.iter().map(PartRange::len).sum();
+        let before = received.len();
+        conn.stream.send(DownloadParts { parts: missing })?;
+
+        for _ in 0..expected {
+            let FilePart {
+                offset,
+                checksum,
+                compression,
+                data,
+            } = conn.stream.recv()?;
+            let heartbeat = conn.agreed.heartbeat_interval();
+            conn.stream
+                .throttle(&[&conn.limiter], data.len() as u64, heartbeat)?;
+            if offset % packet_size != 0 {
+                Err(format!(
+                    "Part at {offset} doesn't start on a multiple of the packet size ({packet_size})"
+                ))?
+            }
+            let part_num = offset / packet_size;
+            if part_num >= total_packets {
+                Err(format!(
+                    "Part Num: {part_num} is past the last part ({})",
+                    total_packets - 1
+                ))?
+            }
+            if conn.agreed.checksum().compute(&data) != checksum {
+                logger::warning(format!(
+                    "Part {part_num} was corrupted in transit, asking for it again"
+                ));
+                continue;
+            }
+            if compression != Compression::None && compression != conn.agreed.compression() {
+                Err(format!(
+                    "Part {part_num} is compressed with {compression} but {} was agreed on",
+                    conn.agreed.compression()
+                ))?
+            }
+            let data = compression.decompress(&data, packet_size)?;
+
+            received.insert(part_num);
+            state.received = PartRange::from_parts(received.iter().copied());
+            conn.stream
+                .with_heartbeats(conn.agreed.heartbeat_interval(), || {
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.write_all(&data)?;
+                    save_state(dest, &state)
+                })??;
+            bar.inc(data.len() as u64);
+        }
+
+        match received.len() == before {
+            true => retries += 1,
+            false => retries = 0,
+        }
+        if retries > MAX_PART_RETRIES {
+            Err(format!(
+                "No parts came through intact {retries} times in a row"
+            ))?
+        }
+    }
+    finish(conn)?;
+    bar.finish_and_clear();
+    drop(file);
+
+    let hash = conn
+        .stream
+        .with_heartbeats(conn.agreed.heartbeat_interval(), || hash_file(dest))??;
+    if hash != state.hash {
+        // Start over next time rather than trust any of it
+        state.received.clear();
+        save_state(dest, &state)?;
+        return Ok(FileResult::Failed(
+            "File was corrupted in transit, rerun to download it again".to_string(),
+        ));
+    }
+    fs::remove_file(state_path(dest))?;
+    Ok(FileResult::Verified)
+}
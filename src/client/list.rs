@@ -0,0 +1,97 @@
+use std::error::Error;
+
+use chrono::DateTime;
+
+use stable_ftp::{
+    file_size_text,
+    structs::{ListEntry, ListFilter, ListRequest, ListResponse, UploadState},
+};
+
+use crate::{Connection, ListArgs};
+
+fn state_name(state: UploadState) -> &'static str {
+    match state {
+        UploadState::Uploading => "uploading",
+        UploadState::Complete => "complete",
+        UploadState::Corrupt => "corrupt",
+    }
+}
+
+fn created_date(entry: &ListEntry) -> String {
+    DateTime::from_timestamp(entry.created_date, 0)
+        .map_or_else(|| entry.created_date.to_string(), |date| date.to_rfc3339())
+}
+
+fn print_json(entries: &[ListEntry]) {
+    let entries = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "name": entry.name,
+                "size": entry.size,
+                "received_bytes": entry.received_bytes,
+                "state": state_name(entry.state),
+                "created_date": created_date(entry),
+                "uploader": entry.uploader,
+            })
+        })
+        .collect::<Vec<_>>();
+    println!("{}", serde_json::Value::Array(entries));
+}
+
+fn print_table(entries: &[ListEntry]) {
+    let header = ["NAME", "SIZE", "RECEIVED", "STATE", "CREATED", "UPLOADER"].map(String::from);
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            [
+                entry.name.clone(),
+                file_size_text(entry.size),
+                file_size_text(entry.received_bytes),
+                state_name(entry.state).to_string(),
+                created_date(entry),
+                entry.uploader.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let mut widths = header.clone().map(|column| column.chars().count());
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(column, width)| format!("{column:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Asks the server what it has and prints it
+pub fn list(conn: &mut Connection, args: &ListArgs) -> Result<(), Box<dyn Error>> {
+    conn.stream.send(ListRequest {
+        prefix: args.prefix.clone(),
+        state: match (args.complete, args.incomplete) {
+            (true, _) => ListFilter::Complete,
+            (_, true) => ListFilter::Incomplete,
+            _ => ListFilter::All,
+        },
+        uploader: args.uploader.clone(),
+    })?;
+
+    let entries = match conn.stream.recv::<ListResponse>()? {
+        ListResponse::Entries(entries) => entries,
+        ListResponse::FailMessage(message) => Err(message)?,
+    };
+    match args.json {
+        true => print_json(&entries),
+        false => print_table(&entries),
+    }
+    Ok(())
+}
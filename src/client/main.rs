@@ -1,25 +1,39 @@
 use std::{
+    collections::VecDeque,
     error::Error,
     fs,
-    io::{Read, Seek, Write},
     net::TcpStream,
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressStyle};
-use lazy_marshal::prelude::*;
+use rustls::ClientConfig;
+use sha2::{Digest, Sha256};
 
 use stable_ftp::{
-    DEFAULT_PACKET_SIZE, MIN_PACKET_SIZE, StreamIterator, file_size_text,
+    Capabilities, DEFAULT_PACKET_SIZE, DEFAULT_WINDOW, HEARTBEATS_PER_TIMEOUT, MAX_PACKET_SIZE,
+    MIN_PACKET_SIZE, PROTOCOL_REVISION, RateLimiter, StreamIterator, file_size_text, hash_ranges,
     logger::{self, Loggable},
-    num_packets,
+    parse_rate, rate_text,
     structs::{
-        AuthRequest, AuthResponse, FileDescription, FileDescriptionResponse, FilePart,
-        FilePartResponse, FileStatus, FileStatusEnum,
+        AuthRequest, AuthResponse, ByteRange, Checksum, Compression, FileDescription,
+        FileDescriptionResponse, FilePart, FilePartResponse, FileStatus, FileStatusEnum,
+        ResumeDecision, UploadResult,
     },
+    tls,
 };
 
+use adaptive::PacketSizer;
+
+mod adaptive;
+mod download;
+mod list;
+mod manage;
+mod reconnect;
+
 #[derive(Parser, Debug, Clone)]
 #[command(
     version,
@@ -31,137 +45,832 @@ struct Args {
     #[arg(short, long)]
     target: String,
 
-    /// The file to send
-    #[arg(short, long)]
-    file: PathBuf,
-
     /// Personal Access Token to the Server (optional with environment variables)
     #[arg(long)]
     token: Option<String>,
 
     /// Packet size to use went sending the file.
-    /// Larger packets have to do less writing to the database, but may have to send more data if the connection drops
+    /// Larger packets have to do less writing to the database, but may have to send more data if the connection drops.
+    /// With `put --adaptive` this is only where it starts
     #[arg(short, long)]
     #[arg(default_value_t = DEFAULT_PACKET_SIZE)]
     packet_size: u64,
+
+    /// Compression codecs to offer the server, most preferred first, comma separated (zstd, deflate, none).
+    /// Parts that don't get any smaller are sent uncompressed anyway
+    #[arg(long, value_delimiter = ',')]
+    #[arg(default_value = "zstd,deflate")]
+    compression: Vec<Compression>,
+
+    /// Checksums to offer the server for checking parts, most preferred first, comma separated (xxh3, crc32c)
+    #[arg(long, value_delimiter = ',')]
+    #[arg(default_value = "xxh3,crc32c")]
+    checksum: Vec<Checksum>,
+
+    /// Most data to send or recieve a second, like `20MB/s`, shared by every connection
+    #[arg(long, value_parser = parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Connect over TLS, trusting the CA certificates in this PEM file
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// Connect over TLS, trusting only the server certificate with this SHA-256 fingerprint (hex).
+    /// The server logs its fingerprint on startup
+    #[arg(long)]
+    tls_pin: Option<String>,
+
+    /// Name to check the server certificate against. Defaults to the host part of `--target`
+    #[arg(long)]
+    tls_server_name: Option<String>,
+
+    /// Seconds to wait without hearing anything from the server before giving up on it.
+    /// A server that's busy sends heartbeats, so running into this means it's gone
+    #[arg(long)]
+    #[arg(default_value_t = 30)]
+    timeout: u64,
+
+    /// How many times in a row to try reconnecting after the connection drops before giving up
+    #[arg(long)]
+    #[arg(default_value_t = 10)]
+    max_retries: u32,
+
+    /// Stop trying to reconnect once this many seconds have passed since the client started
+    #[arg(long)]
+    retry_deadline: Option<u64>,
+
+    #[command(subcommand)]
+    command: Command,
 }
 
-fn connect() -> Result<FileStatus, Box<dyn std::error::Error>> {
-    let args = Args::parse();
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Upload files to the server
+    Put(PutArgs),
+    /// Download files from the server
+    Get(GetArgs),
+    /// Show the files on the server and how far along their uploads are
+    List(ListArgs),
+    /// Remove files you uploaded from the server
+    Delete(DeleteArgs),
+    /// Move a file you uploaded to a new name on the server
+    Rename(RenameArgs),
+}
 
-    let token = match args.token {
-        Some(tok) => tok,
-        None => {
-            std::env::vars()
-                .find(|(k, _)| k == "STABLE_FTP_TOKEN").unwrap_or_else(|| logger::error("Token not specified! Specify it with `--token <TOKEN>` or set as environment variable `STABLE_FTP_TOKEN`"))
-                .1
-        }
-    };
+#[derive(clap::Args, Debug, Clone)]
+struct PutArgs {
+    /// The files to send, one after another over the same session. Globs like `logs/*.txt` are expanded
+    /// and directories are sent with everything in them, keeping their layout on the server
+    #[arg(short, long, required = true, num_args = 1..)]
+    file: Vec<PathBuf>,
 
-    if args.packet_size < MIN_PACKET_SIZE {
-        logger::error(format!(
-            "packet size ({}) must be >= {MIN_PACKET_SIZE}",
-            args.packet_size
-        ))
-    }
+    /// How many parts to send ahead before waiting for the server to acknowledge them.
+    /// Raise this on high latency links
+    #[arg(short, long)]
+    #[arg(default_value_t = DEFAULT_WINDOW)]
+    window: u64,
+
+    /// How many connections to send the file over at once, each one sending a different range of parts.
+    /// Helps on links where a single TCP stream can't use all the bandwidth
+    #[arg(short, long)]
+    #[arg(default_value_t = 1)]
+    connections: usize,
+
+    /// Start the upload over if the part already on the server no longer matches the local file.
+    /// Without this the client refuses to resume
+    #[arg(long)]
+    restart_on_mismatch: bool,
 
+    /// Pick the packet size as the upload goes, growing it while parts go through quickly and
+    /// shrinking it when they're slow, corrupted or the connection drops
+    #[arg(long)]
+    adaptive: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct GetArgs {
+    /// Names of the files on the server, as they were uploaded
+    #[arg(required = true)]
+    names: Vec<String>,
+
+    /// Folder to save them in, under the same relative paths they have on the server.
+    /// An interrupted download leaves a `.stable-ftp` file next to it to resume from
+    #[arg(short, long)]
+    #[arg(default_value = ".")]
+    output: PathBuf,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ListArgs {
+    /// Only files whose name starts with this
+    #[arg(long, default_value = "")]
+    prefix: String,
+
+    /// Only uploaded and verified files
+    #[arg(long, conflicts_with = "incomplete")]
+    complete: bool,
+
+    /// Only files still uploading or that failed verification
+    #[arg(long)]
+    incomplete: bool,
+
+    /// Only files uploaded by this user, by their notes or id
+    #[arg(long, default_value = "")]
+    uploader: String,
+
+    /// Print JSON instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct DeleteArgs {
+    /// Names of the files on the server
+    #[arg(required = true)]
+    names: Vec<String>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct RenameArgs {
+    /// Current name of the file on the server
+    from: String,
+    /// Name to move it to, which must not be taken yet
+    to: String,
+}
+
+/// Everything needed to open another connection to the server
+#[derive(Clone)]
+struct ConnectOptions {
+    target: String,
+    token: String,
+    capabilities: Capabilities,
+    timeout: Duration,
+    retry: reconnect::RetryPolicy,
+    tls: Option<Arc<ClientConfig>>,
+    server_name: String,
+    limiter: Arc<RateLimiter>,
+}
+
+/// An authenticated connection to the server
+struct Connection {
+    stream: StreamIterator,
+    /// What both sides support, parts go with the first codec and checksum in it
+    agreed: Capabilities,
+    /// Shared with every other connection, parts count against it both ways
+    limiter: Arc<RateLimiter>,
+}
+
+/// Connects to the server and authenticates
+fn open_connection(options: &ConnectOptions) -> Result<Connection, Box<dyn Error>> {
     let auth_request = AuthRequest {
         version: env!("CARGO_PKG_VERSION").into(),
-        token,
+        token: options.token.clone(),
+        capabilities: options.capabilities.to_wire(),
     };
-    logger::info(format!("Connecting to {}", args.target));
-    let mut stream = TcpStream::connect(args.target)?;
-    logger::info(format!("Connected to {}", stream.peer_addr()?));
-    stream.write(&auth_request.marshal().collect::<Vec<_>>())?;
+    logger::info(format!("Connecting to {}", options.target));
+    let tcp = TcpStream::connect(&options.target)?;
+    logger::info(format!("Connected to {}", tcp.peer_addr()?));
+    tcp.set_read_timeout(Some(options.timeout))?;
 
-    let mut response_stream = StreamIterator(stream.try_clone().unwrap().bytes());
+    let mut stream = match &options.tls {
+        Some(config) => tls::connect(config.clone(), &options.server_name, tcp)?,
+        None => StreamIterator(Box::new(tcp)),
+    };
+    stream.send(auth_request)?;
 
-    match AuthResponse::unmarshal(&mut response_stream)? {
+    // A busy server answers with a `Busy`, which comes back as the error
+    let agreed = match stream.recv::<AuthResponse>()? {
         AuthResponse {
             success: false,
             failure_reason: msg,
+            ..
         } => logger::error(format!("Authentication failure: {msg}")),
-        _ => logger::info("Auth succeeded!"),
+        AuthResponse { capabilities, .. } => {
+            let agreed = Capabilities::from_wire(&capabilities);
+            if agreed.checksum.is_empty() {
+                Err("The server agreed to no checksum")?
+            }
+            logger::info(format!(
+                "Auth succeeded! Using {} compression and {} checksums",
+                agreed.compression(),
+                agreed.checksum()
+            ));
+            agreed
+        }
     };
+    Ok(Connection {
+        stream,
+        agreed,
+        limiter: options.limiter.clone(),
+    })
+}
 
-    let file_description =
-        FileDescription::try_from(&args.file)?.with_packet_size(args.packet_size);
+fn describe_file(
+    conn: &mut Connection,
+    file_description: &FileDescription,
+) -> Result<FileStatus, Box<dyn Error>> {
+    conn.stream.send(file_description.clone())?;
 
-    stream.write(&file_description.clone().marshal().collect::<Vec<_>>())?;
+    match conn.stream.recv::<FileDescriptionResponse>()? {
+        FileDescriptionResponse::Status(file_status) => Ok(file_status),
+        FileDescriptionResponse::FailMessage(message) => Err(message)?,
+    }
+}
 
-    let f_response = FileDescriptionResponse::unmarshal(&mut response_stream)?;
+/// Sends the byte ranges in `ranges` over one connection, cut into parts of whatever size `sizer`
+/// says at the time but never over `packet_size`, keeping up to `window` of them waiting on the server
+fn send_parts(
+    conn: &mut Connection,
+    path: &Path,
+    packet_size: u64,
+    sizer: &PacketSizer,
+    ranges: Vec<ByteRange>,
+    window: u64,
+    bar: &ProgressBar,
+) -> Result<UploadResult, Box<dyn Error>> {
+    let mut remaining: u64 = ranges.iter().map(ByteRange::len).sum();
+    conn.stream.send(ResumeDecision::Resume(remaining))?;
+    let window = window.min(conn.agreed.window).max(1) as usize;
 
-    // Should always return the Some variant
-    let file_status = match f_response {
-        FileDescriptionResponse::Status(file_status) => file_status,
-        FileDescriptionResponse::FailMessage(message) => logger::error(message),
-    };
+    let mut file = fs::File::open(path)?;
+    let mut buf: Vec<u8> = Vec::new();
+
+    let mut queue = VecDeque::from(ranges);
+    let mut in_flight = VecDeque::new();
+    let mut last_ack = Instant::now();
+    while remaining > 0 {
+        while in_flight.len() < window {
+            let Some(next) = queue.front_mut() else {
+                break;
+            };
+            let len = next.len().min(sizer.size()).min(packet_size);
+            let range = ByteRange {
+                start: next.start,
+                end: next.start + len,
+            };
+            next.start = range.end;
+            if next.is_empty() {
+                queue.pop_front();
+            }
+            if buf.len() < len as usize {
+                buf.resize(len as usize, 69);
+            }
+
+            let part = conn
+                .stream
+                .with_heartbeats(conn.agreed.heartbeat_interval(), || {
+                    FilePart::read(
+                        &mut file,
+                        &mut buf[..range.len() as usize],
+                        range.start,
+                        conn.agreed.compression(),
+                        conn.agreed.checksum(),
+                    )
+                })??;
+            let heartbeat = conn.agreed.heartbeat_interval();
+            conn.stream
+                .throttle(&[&conn.limiter], part.data.len() as u64, heartbeat)?;
+            conn.stream.send(part)?;
+            in_flight.push_back((range, Instant::now()));
+        }
+
+        // Parts are answered in the order they were sent
+        let Some((range, sent)) = in_flight.pop_front() else {
+            Err("Nothing was waiting on the server but parts are still left")?
+        };
+        match conn.stream.recv::<FilePartResponse>()? {
+            FilePartResponse::Success(offset) | FilePartResponse::Resend(offset)
+                if offset != range.start =>
+            {
+                Err(format!(
+                    "Server answered for the part at {offset} but the part at {} was next",
+                    range.start
+                ))?
+            }
+            FilePartResponse::Success(_) => {
+                // The server only gets to a part once it's done with the one before
+                sizer.acknowledged(range.len(), last_ack.max(sent).elapsed());
+                last_ack = Instant::now();
+                remaining -= range.len();
+                bar.inc(range.len());
+                bar.set_message(format!("{}/part", file_size_text(sizer.size())));
+            }
+            FilePartResponse::Resend(offset) => {
+                logger::warning(format!(
+                    "Part at {offset} was corrupted in transit, resending"
+                ));
+                sizer.lost();
+                last_ack = Instant::now();
+                queue.push_front(range);
+            }
+            FilePartResponse::Failure(message) => Err(format!("Failed to upload file: {message}"))?,
+        }
+    }
+
+    conn.stream.recv()
+}
+
+/// Makes sure the parts of the file the server already has still match our copy before resuming
+fn resume_or_restart(
+    conn: &mut Connection,
+    path: &Path,
+    file_status: FileStatus,
+    restart_on_mismatch: bool,
+) -> Result<FileStatus, Box<dyn Error>> {
+    let mut file = fs::File::open(path)?;
+    let received = ByteRange::complement(&file_status.missing, file.metadata()?.len());
+    let received_hash = conn
+        .stream
+        .with_heartbeats(conn.agreed.heartbeat_interval(), || {
+            let mut hasher = Sha256::new();
+            hash_ranges(&mut hasher, &mut file, &received).map(|()| hasher.finalize().to_vec())
+        })??;
+
+    if received_hash == file_status.received_hash {
+        return Ok(file_status);
+    }
+
+    if !restart_on_mismatch {
+        conn.stream.send(ResumeDecision::Abort)?;
+        // The server answers with a FailMessage, wait for it so it isn't left writing to nothing
+        let _ = conn.stream.recv::<FileDescriptionResponse>();
+        Err(format!(
+            "\"{}\" changed since the last attempt, so the {} already on the server can't be resumed. Rerun with `--restart-on-mismatch` to send it again from the start",
+            path.display(),
+            file_size_text(file_status.received_bytes)
+        ))?
+    }
+
+    logger::warning(format!(
+        "\"{}\" changed since the last attempt, starting it over",
+        path.display()
+    ));
+    conn.stream.send(ResumeDecision::Restart)?;
+    match conn.stream.recv::<FileDescriptionResponse>()? {
+        FileDescriptionResponse::Status(file_status) => Ok(file_status),
+        FileDescriptionResponse::FailMessage(message) => Err(message)?,
+    }
+}
+
+/// How one file of the session went
+enum FileResult {
+    Verified,
+    AlreadyExists,
+    Failed(String),
+}
+
+/// `path` with `/` between its parts whatever the local separator is, which is how names go on the wire
+fn upload_name(path: &Path) -> Result<String, Box<dyn Error>> {
+    let parts = path
+        .components()
+        .filter_map(|part| match part {
+            Component::Normal(part) => Some(
+                part.to_str()
+                    .ok_or_else(|| format!("\"{}\" isn't valid UTF-8", path.display())),
+            ),
+            _ => None,
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(parts.join("/"))
+}
+
+/// Every file somewhere under `dir`, in a stable order. Links to directories are skipped, since
+/// following them could go around in circles or out of `dir` altogether
+fn walk_dir(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            walk_dir(&path, files)?;
+        } else if path.is_dir() {
+            logger::warning(format!(
+                "Skipping {}, a link to a directory",
+                path.display()
+            ));
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Every `--file` that exists as is, or else everything it matches as a glob, with the name to
+/// upload it as. Directories are walked and their files named by their path from the directory,
+/// under the directory's own name
+fn expand_files(patterns: &[PathBuf]) -> Result<Vec<(PathBuf, String)>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let matches = match pattern.exists() {
+            true => vec![pattern.clone()],
+            false => glob::glob(&pattern.to_string_lossy())?.collect::<Result<Vec<_>, _>>()?,
+        };
+        if matches.is_empty() {
+            Err(format!("No files match \"{}\"", pattern.display()))?
+        }
+
+        for path in matches {
+            if !path.is_dir() {
+                let name = upload_name(Path::new(path.file_name().unwrap_or_default()))?;
+                files.push((path, name));
+                continue;
+            }
+
+            // Resolved first so `.` and `..` still get named after the real directory
+            let root = fs::canonicalize(&path)?
+                .file_name()
+                .map(PathBuf::from)
+                .unwrap_or_default();
+            let mut found = Vec::new();
+            walk_dir(&path, &mut found)?;
+            for file in found {
+                let name = upload_name(&root.join(file.strip_prefix(&path)?))?;
+                files.push((file, name));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Sends one file over `conn`, plus however many extra connections `--connections` asks for
+fn upload_file(
+    conn: &mut Connection,
+    options: &ConnectOptions,
+    args: &PutArgs,
+    sizer: &Arc<PacketSizer>,
+    path: &Path,
+    file_description: &FileDescription,
+    bar: &mut Option<ProgressBar>,
+) -> Result<FileResult, Box<dyn Error>> {
+    let Capabilities {
+        min_packet_size,
+        max_packet_size,
+        ..
+    } = conn.agreed;
+    sizer.set_bounds(min_packet_size, max_packet_size);
+    if !sizer.is_adaptive() && sizer.size() != file_description.packet_size {
+        logger::warning(format!(
+            "The server takes parts between {} and {}, sending with {}",
+            file_size_text(min_packet_size),
+            file_size_text(max_packet_size),
+            file_size_text(sizer.size())
+        ));
+    }
+    // Adaptive parts can be any size the server takes, so ask for room for the largest
+    let file_description = &file_description
+        .clone()
+        .with_packet_size(match sizer.is_adaptive() {
+            true => max_packet_size,
+            false => sizer.size(),
+        });
+
+    let mut file_status = describe_file(conn, file_description)?;
+    if let FileStatusEnum::Resumeable = file_status.get_status() {
+        file_status = resume_or_restart(conn, path, file_status, args.restart_on_mismatch)?;
+    }
     let FileStatus {
-        request_packet,
+        received_bytes,
         packet_size,
         ..
     } = file_status;
 
-    let num_packets = num_packets(packet_size, file_description.size);
-
-    let file_exists = match file_status.get_status() {
-        FileStatusEnum::Exists => {
-            assert!(num_packets - request_packet == 0);
-            true
-        }
+    match file_status.get_status() {
+        FileStatusEnum::Exists if !file_status.missing.is_empty() => Err(format!(
+            "The server says the file exists but is still missing {} bytes of it",
+            file_status.missing.iter().map(ByteRange::len).sum::<u64>()
+        ))?,
+        FileStatusEnum::Exists => return Ok(FileResult::AlreadyExists),
         FileStatusEnum::Resumeable => {
+            // Whatever size earlier attempts used, what's left goes in parts of this attempt's size
             logger::info(format!(
-                "File already exists! Resuming with packet size {} on packet number {request_packet}/{num_packets}",
-                file_size_text(packet_size)
+                "File already exists! Resuming at {}/{} with packet size {}",
+                file_size_text(received_bytes),
+                file_size_text(file_description.size),
+                file_size_text(sizer.size())
             ));
-            false
         }
         FileStatusEnum::Nonexistent => {
             logger::info(format!("File created!"));
-            false
         }
     };
 
-    if file_exists {
-        Err(std::io::Error::other("File already exists"))?
+    let bar = progress_bar(bar, file_description.size, received_bytes)?;
+    bar.set_message(format!("{}/part", file_size_text(sizer.size())));
+
+    // Give every connection its own contiguous run of the missing bytes
+    let window = args.window.max(1);
+    let mut chunks = share_out(&file_status.missing, args.connections.max(1)).into_iter();
+    let own_ranges = chunks.next().unwrap_or_default();
+
+    let helpers = chunks
+        .map(|ranges| {
+            let options = options.clone();
+            let (file_description, path) = (file_description.clone(), path.to_path_buf());
+            let (sizer, bar) = (sizer.clone(), bar.clone());
+            std::thread::spawn(move || {
+                let send = || {
+                    let mut conn = reconnect::connect_retrying(&options)?;
+                    describe_file(&mut conn, &file_description)?;
+                    send_parts(&mut conn, &path, packet_size, &sizer, ranges, window, &bar)
+                };
+                send().map_err(reconnect::to_io_error)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut results = vec![send_parts(
+        conn,
+        path,
+        packet_size,
+        sizer,
+        own_ranges,
+        window,
+        &bar,
+    )?];
+    // This connection sits idle until the others are done
+    let helpers = conn
+        .stream
+        .with_heartbeats(conn.agreed.heartbeat_interval(), || {
+            helpers
+                .into_iter()
+                .map(|helper| helper.join())
+                .collect::<Vec<_>>()
+        })?;
+    for helper in helpers {
+        results.push(helper.unwrap_or_else(|_| logger::error("Connection thread panicked"))?);
+    }
+    bar.finish_and_clear();
+
+    // Only the connection that finished the file knows how verification went
+    Ok(
+        match results
+            .into_iter()
+            .find(|res| !matches!(res, UploadResult::Pending(_)))
+        {
+            Some(UploadResult::Verified) => FileResult::Verified,
+            Some(UploadResult::Corrupt(message)) => FileResult::Failed(format!(
+                "File was corrupted in transit, rerun to send it again: {message}"
+            )),
+            Some(UploadResult::Pending(_)) | None => FileResult::Failed(
+                "Every connection finished but the server is still missing parts".to_string(),
+            ),
+        },
+    )
+}
+
+/// Cuts `ranges` into up to `count` runs, one after another, with about as many bytes in each
+fn share_out(ranges: &[ByteRange], count: usize) -> Vec<Vec<ByteRange>> {
+    let total: u64 = ranges.iter().map(ByteRange::len).sum();
+    let share = total.div_ceil(count as u64).max(1);
+
+    let mut shares = Vec::with_capacity(count);
+    let mut current = Vec::new();
+    let mut left = share;
+    for &range in ranges {
+        let mut range = range;
+        while !range.is_empty() {
+            let len = range.len().min(left);
+            current.push(ByteRange {
+                start: range.start,
+                end: range.start + len,
+            });
+            range.start += len;
+            left -= len;
+            if left == 0 {
+                shares.push(std::mem::take(&mut current));
+                left = share;
+            }
+        }
     }
+    if !current.is_empty() {
+        shares.push(current);
+    }
+    shares
+}
 
-    let filename = args.file;
-    let mut file = fs::File::open(&filename)?;
-    file.seek(std::io::SeekFrom::Start(request_packet * packet_size))?;
-    let mut buf: Vec<u8> = vec![69; packet_size as usize];
+/// The bar in `bar`, moved to `done` out of `total` bytes, or a new one if there isn't one yet.
+/// Kept across reconnects so it carries on from where it was
+fn progress_bar(
+    bar: &mut Option<ProgressBar>,
+    total: u64,
+    done: u64,
+) -> Result<ProgressBar, Box<dyn Error>> {
+    if let Some(bar) = bar.as_ref().filter(|bar| !bar.is_finished()) {
+        bar.set_length(total);
+        bar.set_position(done);
+        return Ok(bar.clone());
+    }
 
     let style = ProgressStyle::with_template(
-        "[{elapsed_precise}] [{human_pos}/{human_len}] {wide_bar} ETA: {eta_precise}",
+        "[{elapsed_precise}] [{bytes}/{total_bytes}] {msg} {wide_bar} ETA: {eta_precise}",
     )?;
-    let bar = ProgressBar::new(num_packets)
+    let new = ProgressBar::new(total)
         .with_style(style)
-        .with_position(request_packet);
-    for part_num in request_packet..num_packets {
-        let r = file.read(&mut buf[..packet_size as usize])?;
+        .with_position(done);
+    *bar = Some(new.clone());
+    Ok(new)
+}
+
+/// Runs `transfer` on every item over one session. When the connection drops it reconnects and
+/// runs `transfer` on the same item again. After any other failure the rest go over a fresh
+/// session, since there's no telling where the old one was left
+fn run_session<T>(
+    options: &ConnectOptions,
+    items: &[T],
+    verb: &str,
+    name: impl Fn(&T) -> String,
+    mut transfer: impl FnMut(
+        &mut Connection,
+        &T,
+        &mut Option<ProgressBar>,
+    ) -> Result<FileResult, Box<dyn Error>>,
+) -> Result<Vec<(String, FileResult)>, Box<dyn Error>> {
+    let mut conn = Some(reconnect::connect_retrying(options)?);
+
+    let mut results = Vec::with_capacity(items.len());
+    for (i, item) in items.iter().enumerate() {
+        let name = name(item);
+        let Some(session) = conn.as_mut() else {
+            results.push((
+                name,
+                FileResult::Failed("Gave up on the server before getting to it".to_string()),
+            ));
+            continue;
+        };
 
-        if r < packet_size as usize {
-            assert!(file.read(&mut buf[..])? == 0) // Ensure we've actually read to the end of the file
+        logger::info(format!("{verb} \"{name}\" ({}/{})", i + 1, items.len()));
+        let mut bar = None;
+        let result = reconnect::retrying(session, options, |conn| transfer(conn, item, &mut bar));
+        if let Some(bar) = bar {
+            bar.finish_and_clear();
         }
-        let file_part = FilePart {
-            part_num,
-            data: buf[..r].to_vec(),
+
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => {
+                logger::warning(format!("Failed to transfer \"{name}\": {err}"));
+                if err.is::<reconnect::GaveUp>() {
+                    conn = None;
+                } else if i + 1 < items.len() {
+                    conn = reconnect::connect_retrying(options)
+                        .with_warning("Failed to reconnect")
+                        .ok();
+                }
+                FileResult::Failed(err.to_string())
+            }
         };
-        stream.write(&file_part.marshal().collect::<Vec<_>>())?;
+        results.push((name, result));
+    }
+    Ok(results)
+}
+
+/// Runs `op` over a connection of its own, reconnecting and running it again if it drops
+fn with_connection<T>(
+    options: &ConnectOptions,
+    op: impl FnMut(&mut Connection) -> Result<T, Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    reconnect::retrying(&mut reconnect::connect_retrying(options)?, options, op)
+}
+
+fn connect() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let token = match args.token.clone() {
+        Some(tok) => tok,
+        None => {
+            std::env::vars()
+                .find(|(k, _)| k == "STABLE_FTP_TOKEN").unwrap_or_else(|| logger::error("Token not specified! Specify it with `--token <TOKEN>` or set as environment variable `STABLE_FTP_TOKEN`"))
+                .1
+        }
+    };
 
-        let res = FilePartResponse::unmarshal(&mut response_stream)?;
-        if res.success == false {
-            logger::error(format!("Failed to upload file: {}", res.message))
+    if !(MIN_PACKET_SIZE..=MAX_PACKET_SIZE).contains(&args.packet_size) {
+        logger::error(format!(
+            "packet size ({}) must be between {MIN_PACKET_SIZE} and {MAX_PACKET_SIZE}",
+            args.packet_size
+        ))
+    }
+    if args.timeout == 0 {
+        logger::error("timeout must be at least a second")
+    }
+    let timeout = Duration::from_secs(args.timeout);
+
+    let tls = match (&args.tls_ca, &args.tls_pin) {
+        (None, None) => None,
+        (ca, pin) => Some(tls::client_config(ca.as_deref(), pin.as_deref())?),
+    };
+    let options = ConnectOptions {
+        server_name: args.tls_server_name.clone().unwrap_or_else(|| {
+            // Strip the port, and the brackets around IPv6 addresses
+            let host = args
+                .target
+                .rsplit_once(':')
+                .map_or(&*args.target, |(host, _)| host);
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string()
+        }),
+        target: args.target.clone(),
+        token,
+        capabilities: Capabilities {
+            compression: args.compression.clone(),
+            checksum: args.checksum.clone(),
+            heartbeat_interval: timeout.as_millis() as u64 / HEARTBEATS_PER_TIMEOUT,
+            protocol: vec![PROTOCOL_REVISION],
+            ..Capabilities::default()
+        },
+        timeout,
+        retry: reconnect::RetryPolicy {
+            max_retries: args.max_retries,
+            deadline: args
+                .retry_deadline
+                .map(|secs| Instant::now() + Duration::from_secs(secs)),
+        },
+        tls,
+        limiter: Arc::new(RateLimiter::new(args.limit_rate)),
+    };
+    if let Some(rate) = args.limit_rate {
+        logger::info(format!("Limiting transfers to {}", rate_text(rate)));
+    }
+
+    match &args.command {
+        Command::Put(put) => {
+            // Hashing can take a while, so do it before the server starts waiting on us
+            let files = expand_files(&put.file)?
+                .into_iter()
+                .map(|(path, name)| {
+                    let file_description = FileDescription::try_from(&path)?
+                        .with_name(name)
+                        .with_packet_size(args.packet_size);
+                    Ok((path, file_description))
+                })
+                .collect::<Result<Vec<_>, std::io::Error>>()?;
+            // Shared by every file, so each one starts with what the last one learned
+            let sizer = Arc::new(match put.adaptive {
+                true => PacketSizer::adaptive(args.packet_size),
+                false => PacketSizer::fixed(args.packet_size),
+            });
+
+            report(run_session(
+                &options,
+                &files,
+                "Sending",
+                |(path, _)| path.display().to_string(),
+                |conn, (path, file_description), bar| {
+                    upload_file(conn, &options, put, &sizer, path, file_description, bar)
+                        .inspect_err(|err| {
+                            // Coming back on a new connection, so try smaller parts
+                            if reconnect::is_connection_error(&**err) {
+                                sizer.lost();
+                            }
+                        })
+                },
+            )?)
+        }
+        Command::Get(get) => report(run_session(
+            &options,
+            &get.names,
+            "Fetching",
+            String::clone,
+            |conn, name, bar| {
+                download::download_file(conn, name, &get.output.join(name), args.packet_size, bar)
+            },
+        )?),
+        Command::List(list) => with_connection(&options, |conn| list::list(conn, list))?,
+        // Sent only once, a repeat of one that got through before the connection dropped would fail
+        Command::Delete(delete) => {
+            manage::delete(&mut reconnect::connect_retrying(&options)?, delete)?
+        }
+        Command::Rename(rename) => {
+            manage::rename(&mut reconnect::connect_retrying(&options)?, rename)?
         }
-        bar.inc(1);
     }
-    bar.finish_and_clear();
+    Ok(())
+}
 
-    Ok(file_status)
+/// Logs how every file went, and fails if any of them did
+fn report(results: Vec<(String, FileResult)>) {
+    let mut failed = 0;
+    for (name, result) in &results {
+        match result {
+            FileResult::Verified => logger::info(format!("\"{name}\": done, hash verified")),
+            FileResult::AlreadyExists => {
+                logger::info(format!("\"{name}\": already there, skipped"))
+            }
+            FileResult::Failed(message) => {
+                failed += 1;
+                logger::warning(format!("\"{name}\": failed: {message}"))
+            }
+        }
+    }
+
+    if failed > 0 {
+        logger::error(format!("{failed} of {} files failed", results.len()))
+    }
+    logger::info(format!("All {} files transferred!", results.len()));
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     connect().to_error("");
-    logger::info("Uploaded file successfully!");
     Ok(())
 }
@@ -0,0 +1,49 @@
+use std::error::Error;
+
+use stable_ftp::{
+    logger,
+    structs::{DeleteRequest, FileOpResponse, RenameRequest},
+};
+
+use crate::{Connection, DeleteArgs, RenameArgs, reconnect::is_connection_error};
+
+fn check_response(conn: &mut Connection) -> Result<(), Box<dyn Error>> {
+    match conn.stream.recv::<FileOpResponse>()? {
+        FileOpResponse::Done => Ok(()),
+        FileOpResponse::FailMessage(message) => Err(message)?,
+    }
+}
+
+/// Removes every named file, carrying on past the ones the server refuses but not past a dropped
+/// connection
+pub fn delete(conn: &mut Connection, args: &DeleteArgs) -> Result<(), Box<dyn Error>> {
+    let mut failed = 0;
+    for name in &args.names {
+        conn.stream.send(DeleteRequest { name: name.clone() })?;
+        match check_response(conn) {
+            Ok(()) => logger::info(format!("\"{name}\": deleted")),
+            Err(err) if is_connection_error(&*err) => Err(format!(
+                "Lost the connection before hearing whether \"{name}\" was deleted: {err}"
+            ))?,
+            Err(err) => {
+                failed += 1;
+                logger::warning(format!("\"{name}\": failed: {err}"))
+            }
+        }
+    }
+
+    if failed > 0 {
+        logger::error(format!("{failed} of {} files failed", args.names.len()))
+    }
+    Ok(())
+}
+
+pub fn rename(conn: &mut Connection, args: &RenameArgs) -> Result<(), Box<dyn Error>> {
+    conn.stream.send(RenameRequest {
+        from: args.from.clone(),
+        to: args.to.clone(),
+    })?;
+    check_response(conn)?;
+    logger::info(format!("Renamed \"{}\" to \"{}\"", args.from, args.to));
+    Ok(())
+}
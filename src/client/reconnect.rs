@@ -0,0 +1,232 @@
+use std::{
+    collections::hash_map::RandomState,
+    error::Error,
+    fmt::Display,
+    hash::{BuildHasher, Hasher},
+    io,
+    time::{Duration, Instant},
+};
+
+use stable_ftp::{StreamIterator, logger, structs::Busy};
+
+use crate::{ConnectOptions, Connection, open_connection};
+
+/// Wait before the first reconnect attempt, doubled after every failed one
+const FIRST_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// When to stop trying to get back to the server
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Reconnect attempts in a row before giving up
+    pub max_retries: u32,
+    /// No more attempts after this
+    pub deadline: Option<Instant>,
+}
+
+/// Reconnecting was given up on, so there's no point trying anything else on this server
+#[derive(Debug)]
+pub struct GaveUp(String);
+
+impl Display for GaveUp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for GaveUp {}
+
+/// Whether `err` came from the connection going away or being turned away, rather than something
+/// that would just happen again on a new one
+pub fn is_connection_error(err: &(dyn Error + 'static)) -> bool {
+    use io::ErrorKind::*;
+    busy_for(err).is_some()
+        || err.downcast_ref::<io::Error>().is_some_and(|err| {
+            matches!(
+                err.kind(),
+                ConnectionRefused
+                    | ConnectionReset
+                    | ConnectionAborted
+                    | NotConnected
+                    | BrokenPipe
+                    | TimedOut
+                    | WouldBlock
+                    | UnexpectedEof
+                    | HostUnreachable
+                    | NetworkUnreachable
+            )
+        })
+}
+
+/// Turns an error into one that can leave a thread, keeping the kind of io errors and any
+/// [`Busy`] so [`is_connection_error`] still recognizes them
+pub fn to_io_error(err: Box<dyn Error>) -> io::Error {
+    let err = match err.downcast::<io::Error>() {
+        Ok(err) => return *err,
+        Err(err) => err,
+    };
+    match err.downcast::<Busy>() {
+        Ok(busy) => io::Error::new(io::ErrorKind::ConnectionAborted, *busy),
+        Err(err) => io::Error::other(err.to_string()),
+    }
+}
+
+/// Exponential backoff with jitter, so clients that dropped together don't all come back at once
+fn backoff(attempt: u32) -> Duration {
+    let delay = FIRST_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_DELAY);
+    let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    delay.mul_f64(0.5 + jitter / 2.0)
+}
+
+/// Connects again after the connection dropped, waiting longer after every failed attempt, or
+/// as long as the server asks when it's busy. `attempt` is the number of the next attempt, and is
+/// left at the last one made
+pub fn reconnect(
+    options: &ConnectOptions,
+    attempt: &mut u32,
+    mut retry_after: Option<Duration>,
+) -> Result<Connection, Box<dyn Error>> {
+    let RetryPolicy {
+        max_retries,
+        deadline,
+    } = options.retry;
+    loop {
+        if *attempt > max_retries {
+            Err(GaveUp(format!(
+                "Gave up on the server after {max_retries} reconnect attempts"
+            )))?
+        }
+        let delay = retry_after.take().unwrap_or_else(|| backoff(*attempt));
+        if deadline.is_some_and(|deadline| Instant::now() + delay > deadline) {
+            Err(GaveUp(
+                "Gave up on the server, the retry deadline passed".to_string(),
+            ))?
+        }
+
+        logger::info(format!(
+            "Reconnecting in {:.1}s (attempt {attempt}/{max_retries})",
+            delay.as_secs_f64()
+        ));
+        std::thread::sleep(delay);
+        match open_connection(options) {
+            Ok(conn) => return Ok(conn),
+            Err(err) if is_connection_error(&*err) => {
+                logger::warning(format!("Failed to reconnect: {err}"));
+                retry_after = busy_for(&*err);
+                *attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// How long a [`Busy`] from the server asked to wait, if that's what `err` is
+fn busy_for(err: &(dyn Error + 'static)) -> Option<Duration> {
+    let busy = match err.downcast_ref::<io::Error>() {
+        Some(err) => err.get_ref()?.downcast_ref::<Busy>(),
+        None => err.downcast_ref::<Busy>(),
+    };
+    busy.map(|busy| Duration::from_secs(busy.retry_after))
+}
+
+/// Connects, retrying like [`reconnect`] if the server can't be reached or is busy
+pub fn connect_retrying(options: &ConnectOptions) -> Result<Connection, Box<dyn Error>> {
+    match open_connection(options) {
+        Err(err) if is_connection_error(&*err) => {
+            logger::warning(format!("Failed to connect: {err}"));
+            reconnect(options, &mut 1, busy_for(&*err))
+        }
+        res => res,
+    }
+}
+
+/// Runs `op` over `conn`, reconnecting and running it again whenever the connection drops.
+/// `op` has to pick up from wherever the server or the local state says it got to
+pub fn retrying<T>(
+    conn: &mut Connection,
+    options: &ConnectOptions,
+    mut op: impl FnMut(&mut Connection) -> Result<T, Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    let mut attempt = 1;
+    loop {
+        let connected = Instant::now();
+        match op(conn) {
+            Err(err) if is_connection_error(&*err) => {
+                logger::warning(format!("Lost the connection to the server: {err}"));
+                // A connection that held up for a while got somewhere, so count from scratch
+                if connected.elapsed() > MAX_DELAY {
+                    attempt = 1;
+                }
+                // Hang up before waiting, a server that's shutting down waits on us to close
+                conn.stream = StreamIterator(Box::new(io::empty()));
+                *conn = reconnect(options, &mut attempt, busy_for(&*err))?;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn busy() -> Busy {
+        Busy {
+            retry_after: 7,
+            reason: "Too many connections".to_string(),
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially_with_jitter() {
+        for (attempt, full) in [(0, 1), (1, 1), (2, 2), (4, 8), (7, 60), (u32::MAX, 60)] {
+            let full = Duration::from_secs(full);
+            for _ in 0..20 {
+                let delay = backoff(attempt);
+                assert!(
+                    delay >= full / 2 && delay <= full,
+                    "attempt {attempt} waited {delay:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn recognizes_connection_errors() {
+        let io_err = |kind| -> Box<dyn Error> { Box::new(io::Error::from(kind)) };
+        assert!(is_connection_error(&*io_err(
+            io::ErrorKind::ConnectionReset
+        )));
+        assert!(is_connection_error(&*io_err(io::ErrorKind::TimedOut)));
+        assert!(is_connection_error(&*io_err(io::ErrorKind::UnexpectedEof)));
+        assert!(!is_connection_error(&*io_err(io::ErrorKind::NotFound)));
+        assert!(!is_connection_error(&*io_err(
+            io::ErrorKind::PermissionDenied
+        )));
+
+        let busy: Box<dyn Error> = Box::new(busy());
+        assert!(is_connection_error(&*busy));
+        assert_eq!(busy_for(&*busy), Some(Duration::from_secs(7)));
+        let refused: Box<dyn Error> = "\"a.txt\" isn't on the server".into();
+        assert!(!is_connection_error(&*refused));
+        assert_eq!(busy_for(&*refused), None);
+    }
+
+    #[test]
+    fn keeps_what_matters_across_threads() {
+        let err = to_io_error(Box::new(io::Error::from(io::ErrorKind::BrokenPipe)));
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        let err: Box<dyn Error> = Box::new(to_io_error(Box::new(busy())));
+        assert!(is_connection_error(&*err));
+        assert_eq!(busy_for(&*err), Some(Duration::from_secs(7)));
+
+        let err = to_io_error("Hash mismatch".into());
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(err.to_string(), "Hash mismatch");
+        assert!(!is_connection_error(&err));
+    }
+}
@@ -1,10 +1,13 @@
-use std::sync::{Mutex, OnceLock};
+use std::sync::{Mutex, MutexGuard, OnceLock};
 
 use chrono::{DateTime, Utc};
-use rusqlite::{Connection, params};
+use rusqlite::{Connection, OptionalExtension, params};
 use typed_db::prelude::*;
 
-use crate::{logger::Loggable, structs::Id};
+use crate::{
+    logger::Loggable,
+    structs::{ByteRange, Id},
+};
 
 #[derive(Debug, Clone, DbTable)]
 pub struct DbFile {
@@ -12,16 +15,45 @@ pub struct DbFile {
     pub id: Id,
     #[unique]
     pub filename: String,
+    /// Size of the whole file in bytes
+    pub size: u64,
+    /// How many bytes have been recieved, they can come in any order and any size of part
     #[default(0)]
-    current_packet: u64,
-    pub total_packets: u64,
-    pub packet_size: u64,
+    received_bytes: u64,
+    /// Hex encoded SHA-256 digest the client sent for the file
+    pub hash: String,
+    /// `None` until the last byte arrives, then whether the digest matched
+    #[default(NULL)]
+    verified: Option<bool>,
     #[foreign_key(UserAuth::id)]
     pub inserted_by_id: i32,
     #[default(CURRENT_TIMESTAMP)]
     pub created_date: DateTime<Utc>,
 }
 
+/// One row for every part of a [`DbFile`] that made it to disk. Parts never overlap, but can
+/// be any size since each attempt picks its own
+#[derive(Debug, Clone, DbTable)]
+pub struct ReceivedPart {
+    #[primary_key]
+    pub id: Id,
+    #[foreign_key(DbFile::id)]
+    pub file_id: Id,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// An upload from before uploads were staged, which is still written under its own name until the
+/// server moves it. Only the migration to staging adds these
+#[derive(Debug, Clone, DbTable)]
+pub struct UnstagedUpload {
+    #[primary_key]
+    pub id: Id,
+    #[unique]
+    #[foreign_key(DbFile::id)]
+    pub file_id: Id,
+}
+
 #[derive(Debug, Clone, DbTable)]
 pub struct UserAuth {
     #[primary_key]
@@ -33,20 +65,173 @@ pub struct UserAuth {
     pub created_date: DateTime<Utc>,
 }
 
+/// Limits on what a user may store. Users without a row, and limits left NULL, aren't limited
+#[derive(Debug, Clone, DbTable)]
+pub struct UserQuota {
+    #[primary_key]
+    pub id: Id,
+    #[unique]
+    #[foreign_key(UserAuth::id)]
+    pub user_id: Id,
+    /// Bytes across all their files, finished or not
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+    /// Bytes in any one file
+    pub max_file_size: Option<u64>,
+}
+
 impl DbFile {
-    pub fn current_packet(&self) -> u64 {
-        self.current_packet
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes
+    }
+
+    fn load_received_bytes(&mut self, con: &Connection) -> Result<(), rusqlite::Error> {
+        self.received_bytes = con.query_row(
+            &format!(
+                "SELECT received_bytes FROM {} WHERE id == ?1",
+                Self::TABLE_NAME
+            ),
+            params![self.id],
+            |row| row.get(0),
+        )?;
+        Ok(())
+    }
+
+    /// Records the `len` bytes at `offset` as written to disk. Also picks up parts other
+    /// connections recieved in the meantime. The bool is false if any of it was already there,
+    /// in which case nothing is recorded and whatever is still missing gets asked for again
+    pub fn mark_received(
+        mut self,
+        con: &Connection,
+        offset: u64,
+        len: u64,
+    ) -> Result<(Self, bool), rusqlite::Error> {
+        // Both or neither, so a crash in between can't leave the count behind the parts
+        let tx = con.unchecked_transaction()?;
+        // Parts don't overlap, so only the last one starting before our end could reach into us
+        let overlaps = tx
+            .query_row(
+                &format!(
+                    "SELECT offset + len > ?2 FROM {} WHERE file_id == ?1 AND offset < ?3 ORDER BY offset DESC LIMIT 1",
+                    ReceivedPart::TABLE_NAME
+                ),
+                params![self.id, offset, offset + len],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(false);
+        if !overlaps {
+            tx.execute(
+                &format!(
+                    "INSERT INTO {} (file_id, offset, len) VALUES (?1, ?2, ?3)",
+                    ReceivedPart::TABLE_NAME
+                ),
+                params![self.id, offset, len],
+            )?;
+            tx.execute(
+                &format!(
+                    "UPDATE {} SET received_bytes = received_bytes + ?2 WHERE id == ?1",
+                    Self::TABLE_NAME
+                ),
+                params![self.id, len],
+            )?;
+        }
+        tx.commit()?;
+        self.load_received_bytes(con)?;
+        Ok((self, !overlaps))
+    }
+
+    pub fn missing_ranges(&self, con: &Connection) -> Result<Vec<ByteRange>, rusqlite::Error> {
+        let received = ReceivedPart::select(con, "WHERE file_id = ?1", params![self.id])?;
+        let received = ByteRange::merge(received.into_iter().map(|part| ByteRange {
+            start: part.offset,
+            end: part.offset + part.len,
+        }));
+        Ok(ByteRange::complement(&received, self.size))
+    }
+
+    /// Fetches the row again to see what other connections have done to it
+    pub fn reload(&self, con: &Connection) -> Result<Self, rusqlite::Error> {
+        Self::select(con, "WHERE id = ?1", params![self.id])?
+            .pop()
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)
     }
 
-    pub fn inc_current_packet(mut self, con: &Connection) -> Result<Self, rusqlite::Error> {
+    pub fn verified(&self) -> Option<bool> {
+        self.verified
+    }
+
+    pub fn set_verified(
+        mut self,
+        con: &Connection,
+        verified: bool,
+    ) -> Result<Self, rusqlite::Error> {
+        con.execute(
+            &format!(
+                "UPDATE {} SET verified = ?1 WHERE id == ?2",
+                Self::TABLE_NAME
+            ),
+            params![verified, self.id],
+        )?;
+        self.verified = Some(verified);
+        Ok(self)
+    }
+
+    /// Throws away all progress on the file so it gets sent again from the start
+    pub fn reset_progress(mut self, con: &Connection) -> Result<Self, rusqlite::Error> {
+        con.execute(
+            &format!(
+                "DELETE FROM {} WHERE file_id == ?1",
+                ReceivedPart::TABLE_NAME
+            ),
+            params![self.id],
+        )?;
         con.execute(
             &format!(
-                "UPDATE {} SET current_packet = current_packet + 1 WHERE id == ?1",
+                "UPDATE {} SET received_bytes = 0, verified = NULL WHERE id == ?1",
                 Self::TABLE_NAME
             ),
             params![self.id],
         )?;
-        self.current_packet += 1;
+        self.received_bytes = 0;
+        self.verified = None;
+        Ok(self)
+    }
+
+    /// Points the row at a new version of the source file, which has yet to be verified. Recieved
+    /// parts are kept unless they reach past the new end of the file
+    pub fn update_source(
+        mut self,
+        con: &Connection,
+        hash: String,
+        size: u64,
+    ) -> Result<Self, rusqlite::Error> {
+        con.execute(
+            &format!(
+                "UPDATE {} SET hash = ?1, size = ?2, verified = NULL WHERE id == ?3",
+                Self::TABLE_NAME
+            ),
+            params![hash, size, self.id],
+        )?;
+        con.execute(
+            &format!(
+                "DELETE FROM {} WHERE file_id == ?1 AND offset + len > ?2",
+                ReceivedPart::TABLE_NAME
+            ),
+            params![self.id, size],
+        )?;
+        con.execute(
+            &format!(
+                "UPDATE {} SET received_bytes = (SELECT COALESCE(SUM(len), 0) FROM {} WHERE file_id == ?1) WHERE id == ?1",
+                Self::TABLE_NAME,
+                ReceivedPart::TABLE_NAME
+            ),
+            params![self.id],
+        )?;
+        self.load_received_bytes(con)?;
+        self.hash = hash;
+        self.size = size;
+        self.verified = None;
         Ok(self)
     }
 
@@ -64,6 +249,81 @@ impl DbFile {
             None => None,
         })
     }
+
+    /// Removes the row along with its recieved parts
+    pub fn delete(self, con: &Connection) -> Result<(), rusqlite::Error> {
+        for table in [ReceivedPart::TABLE_NAME, UnstagedUpload::TABLE_NAME] {
+            con.execute(
+                &format!("DELETE FROM {table} WHERE file_id == ?1"),
+                params![self.id],
+            )?;
+        }
+        con.execute(
+            &format!("DELETE FROM {} WHERE id == ?1", Self::TABLE_NAME),
+            params![self.id],
+        )?;
+        Ok(())
+    }
+
+    pub fn rename(mut self, con: &Connection, filename: String) -> Result<Self, rusqlite::Error> {
+        con.execute(
+            &format!(
+                "UPDATE {} SET filename = ?1 WHERE id == ?2",
+                Self::TABLE_NAME
+            ),
+            params![filename, self.id],
+        )?;
+        self.filename = filename;
+        Ok(self)
+    }
+
+    /// Every file whose name starts with `prefix`, by name
+    pub fn with_prefix(db: &Connection, prefix: &str) -> Result<Vec<Self>, rusqlite::Error> {
+        Self::select(
+            db,
+            "WHERE substr(filename, 1, length(?1)) = ?1 ORDER BY filename",
+            params![prefix],
+        )
+    }
+
+    /// How many files `user_id` uploaded and their total size, leaving out the file `except`
+    pub fn usage(
+        db: &Connection,
+        user_id: Id,
+        except: Option<Id>,
+    ) -> Result<(u64, u64), rusqlite::Error> {
+        db.query_row(
+            &format!(
+                "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM {} WHERE inserted_by_id == ?1 AND id IS NOT ?2",
+                Self::TABLE_NAME
+            ),
+            params![user_id, except],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+}
+
+impl UnstagedUpload {
+    /// Every file the migration to staging left for the server to move
+    pub fn files(db: &Connection) -> Result<Vec<DbFile>, rusqlite::Error> {
+        DbFile::select(
+            db,
+            &format!(
+                "WHERE id IN (SELECT file_id FROM {}) ORDER BY id",
+                Self::TABLE_NAME
+            ),
+            params![],
+        )
+    }
+
+    /// Forgets about `file` once it's where it belongs
+    pub fn done(db: &Connection, file: &DbFile) -> Result<(), rusqlite::Error> {
+        db.execute(
+            &format!("DELETE FROM {} WHERE file_id == ?1", Self::TABLE_NAME),
+            params![file.id],
+        )?;
+        Ok(())
+    }
 }
 
 impl UserAuth {
@@ -73,6 +333,143 @@ impl UserAuth {
     }
 }
 
+impl UserQuota {
+    pub fn for_user(db: &Connection, user_id: Id) -> Result<Option<Self>, rusqlite::Error> {
+        let rows = Self::select(db, "WHERE user_id = ? LIMIT 1", params![user_id])?;
+        Ok(rows.into_iter().next())
+    }
+}
+
+type Migration = fn(&Connection) -> Result<(), rusqlite::Error>;
+
+/// Takes the tables from one version to the next, the one at index `n` starting from version `n`.
+/// Version 0 is the layout 0.2.0 left behind, before the version was kept in `user_version`
+const MIGRATIONS: [Migration; 5] = [
+    add_file_hash,
+    add_received_parts,
+    add_file_size,
+    to_byte_ranges,
+    mark_unstaged,
+];
+
+/// Digests weren't kept before, so they're left empty. Files that were complete already count as
+/// verified, they're where verified files go and there's nothing left to check them against
+fn add_file_hash(con: &Connection) -> Result<(), rusqlite::Error> {
+    con.execute_batch(&format!(
+        "ALTER TABLE {table} ADD COLUMN hash TEXT NOT NULL DEFAULT '';
+        ALTER TABLE {table} ADD COLUMN verified BOOLEAN DEFAULT NULL;
+        UPDATE {table} SET verified = 1 WHERE current_packet >= total_packets;",
+        table = DbFile::TABLE_NAME
+    ))
+}
+
+/// Parts used to come in order, so everything before `current_packet` is what was recieved
+fn add_received_parts(con: &Connection) -> Result<(), rusqlite::Error> {
+    con.execute_batch(&format!(
+        "CREATE TABLE {parts} (
+            id INTEGER PRIMARY KEY,
+            file_id INTEGER NOT NULL REFERENCES {files} (id),
+            part_num INTEGER NOT NULL
+        );
+        CREATE UNIQUE INDEX received_part_file_part ON {parts} (file_id, part_num);
+        WITH RECURSIVE received (file_id, part_num, count) AS (
+            SELECT id, 0, current_packet FROM {files} WHERE current_packet > 0
+            UNION ALL
+            SELECT file_id, part_num + 1, count FROM received WHERE part_num + 1 < count
+        )
+        INSERT INTO {parts} (file_id, part_num) SELECT file_id, part_num FROM received;",
+        parts = ReceivedPart::TABLE_NAME,
+        files = DbFile::TABLE_NAME
+    ))
+}
+
+/// The exact size was never kept, whole parts is as close as it gets. The client's next
+/// description of the file sets it right
+fn add_file_size(con: &Connection) -> Result<(), rusqlite::Error> {
+    con.execute_batch(&format!(
+        "ALTER TABLE {table} ADD COLUMN size INTEGER NOT NULL DEFAULT 0;
+        UPDATE {table} SET size = total_packets * packet_size;",
+        table = DbFile::TABLE_NAME
+    ))
+}
+
+/// Parts used to all be `packet_size` long, apart from the last one of a file. Now they're kept
+/// by where they start and how long they are, and only bytes are counted. Both tables are built
+/// again so their columns end up just like in a new database
+fn to_byte_ranges(con: &Connection) -> Result<(), rusqlite::Error> {
+    let (files, parts) = (DbFile::TABLE_NAME, ReceivedPart::TABLE_NAME);
+    con.execute_batch(&format!(
+        "ALTER TABLE {files} RENAME TO old_{files};
+        ALTER TABLE {parts} RENAME TO old_{parts};"
+    ))?;
+    DbFile::create_table(con)?;
+    ReceivedPart::create_table(con)?;
+    con.execute_batch(&format!(
+        "INSERT INTO {files} (id, filename, size, hash, verified, inserted_by_id, created_date)
+        SELECT id, filename, size, hash, verified, inserted_by_id, created_date FROM old_{files};
+        INSERT INTO {parts} (id, file_id, offset, len)
+        SELECT part.id, part.file_id, part.part_num * file.packet_size,
+            MIN(file.packet_size, file.size - part.part_num * file.packet_size)
+        FROM old_{parts} AS part JOIN old_{files} AS file ON file.id = part.file_id
+        WHERE part.part_num * file.packet_size < file.size;
+        UPDATE {files} SET received_bytes = (
+            SELECT COALESCE(SUM(len), 0) FROM {parts} WHERE file_id = {files}.id
+        );
+        DROP TABLE old_{parts};
+        DROP TABLE old_{files};"
+    ))
+}
+
+/// Unfinished uploads used to be written under their own name. Whether one still is can't be told
+/// from the files, a new version is staged beside the finished one, so they're marked here
+fn mark_unstaged(con: &Connection) -> Result<(), rusqlite::Error> {
+    UnstagedUpload::create_table(con)?;
+    con.execute(
+        &format!(
+            "INSERT INTO {} (file_id) SELECT id FROM {} WHERE verified IS NULL",
+            UnstagedUpload::TABLE_NAME,
+            DbFile::TABLE_NAME
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+fn table_exists(con: &Connection, name: &str) -> Result<bool, rusqlite::Error> {
+    con.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        params![name],
+        |row| row.get(0),
+    )
+}
+
+/// Creates the tables, or brings the ones an older version left behind up to date
+pub fn init(con: &Connection) -> Result<(), rusqlite::Error> {
+    // All or nothing, so a failed migration can be run again once whatever stopped it is fixed
+    let tx = con.unchecked_transaction()?;
+    let version: u32 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if table_exists(&tx, DbFile::TABLE_NAME)? {
+        for migration in MIGRATIONS.iter().skip(version as usize) {
+            migration(&tx)?;
+        }
+    }
+
+    UserAuth::create_table(&tx)?;
+    UserQuota::create_table(&tx)?;
+    DbFile::create_table(&tx)?;
+    ReceivedPart::create_table(&tx)?;
+    UnstagedUpload::create_table(&tx)?;
+    tx.execute(
+        &format!(
+            "CREATE UNIQUE INDEX IF NOT EXISTS received_part_file_offset ON {} (file_id, offset)",
+            ReceivedPart::TABLE_NAME
+        ),
+        [],
+    )?;
+    tx.pragma_update(None, "user_version", MIGRATIONS.len() as u32)?;
+    tx.commit()
+}
+
 static WRITE_CONNECTION: OnceLock<Mutex<Connection>> = OnceLock::new();
 const DB_FILENAME: &'static str = "stable-ftp.sqlite";
 
@@ -84,6 +481,87 @@ pub fn get_write_connection() -> &'static Mutex<Connection> {
     })
 }
 
+/// Closes the write connection once whatever write is underway is done, folding the write-ahead
+/// log back into the database first. The lock comes back held, so nothing can write through the
+/// in-memory stand-in left behind. Keep it until the process exits
+pub fn close_write_connection() -> Result<MutexGuard<'static, Connection>, rusqlite::Error> {
+    let mut conn = get_write_connection().lock().unwrap();
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    let closing = std::mem::replace(&mut *conn, Connection::open_in_memory()?);
+    closing.close().map_err(|(_, err)| err)?;
+    Ok(conn)
+}
+
 pub fn get_read_connection() -> Result<Connection, rusqlite::Error> {
     rusqlite::Connection::open_with_flags(DB_FILENAME, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_the_old_layout() {
+        let con = Connection::open_in_memory().unwrap();
+        // What 0.2.0 created, parts always came in order and were counted rather than kept
+        con.execute_batch(&format!(
+            "CREATE TABLE {users} (
+                id INTEGER PRIMARY KEY,
+                token TEXT NOT NULL UNIQUE,
+                notes TEXT,
+                created_date TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE {files} (
+                id INTEGER PRIMARY KEY,
+                filename TEXT NOT NULL UNIQUE,
+                current_packet INTEGER NOT NULL DEFAULT 0,
+                total_packets INTEGER NOT NULL,
+                packet_size INTEGER NOT NULL,
+                inserted_by_id INTEGER NOT NULL REFERENCES {users} (id),
+                created_date TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            INSERT INTO {users} (id, token) VALUES (1, 'token');
+            INSERT INTO {files} (filename, current_packet, total_packets, packet_size, inserted_by_id)
+            VALUES ('done.bin', 3, 3, 4, 1), ('half.bin', 2, 5, 4, 1), ('new.bin', 0, 2, 4, 1);",
+            users = UserAuth::TABLE_NAME,
+            files = DbFile::TABLE_NAME
+        ))
+        .unwrap();
+
+        init(&con).unwrap();
+        // Already up to date, so nothing happens the second time
+        init(&con).unwrap();
+        let version: usize = con
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        let range = |start, end| ByteRange { start, end };
+        let file = |name| DbFile::find_filename(&con, name).unwrap().unwrap();
+        let done = file("done.bin");
+        assert_eq!((done.size, done.received_bytes()), (12, 12));
+        assert_eq!(done.verified(), Some(true));
+        assert_eq!(done.missing_ranges(&con).unwrap(), vec![]);
+
+        let half = file("half.bin");
+        assert_eq!((half.size, half.received_bytes()), (20, 8));
+        assert_eq!(half.verified(), None);
+        assert_eq!(half.missing_ranges(&con).unwrap(), vec![range(8, 20)]);
+        let (half, fresh) = half.mark_received(&con, 8, 12).unwrap();
+        assert!(fresh);
+        assert_eq!(half.received_bytes(), 20);
+        assert_eq!(half.missing_ranges(&con).unwrap(), vec![]);
+
+        let new = file("new.bin");
+        assert_eq!(new.received_bytes(), 0);
+        assert_eq!(new.missing_ranges(&con).unwrap(), vec![range(0, 8)]);
+
+        // Only the unfinished ones were written under their own name
+        let unstaged = UnstagedUpload::files(&con).unwrap();
+        let names = unstaged.iter().map(|file| file.filename.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["half.bin", "new.bin"]);
+        UnstagedUpload::done(&con, &half).unwrap();
+        new.delete(&con).unwrap();
+        assert!(UnstagedUpload::files(&con).unwrap().is_empty());
+    }
+}
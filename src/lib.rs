@@ -1,9 +1,25 @@
+pub mod async_stream;
 pub mod db;
 pub mod logger;
 pub mod structs;
+pub mod tls;
 
 pub const DEFAULT_PACKET_SIZE: u64 = 2_u64.pow(22);
 pub const MIN_PACKET_SIZE: u64 = 2u64.pow(20);
+pub const MAX_PACKET_SIZE: u64 = 2u64.pow(30);
+/// Largest message payload a peer will read, enough for a part of [`MAX_PACKET_SIZE`] and its fields
+pub const MAX_MESSAGE_SIZE: u64 = MAX_PACKET_SIZE + 2u64.pow(16);
+/// How many parts the client sends ahead of the server's acknowledgements
+pub const DEFAULT_WINDOW: u64 = 8;
+/// Most parts the server lets wait on an acknowledgement at once
+pub const MAX_WINDOW: u64 = 64;
+/// How many heartbeats a peer sends per read timeout of the other side, so a couple can go
+/// missing before it gives up
+pub const HEARTBEATS_PER_TIMEOUT: u64 = 3;
+/// Revision of the message layouts, bumped whenever a peer on the previous one couldn't read them
+pub const PROTOCOL_REVISION: u64 = 1;
+/// How many times in a row a single part may fail its checksum before the upload is dropped
+pub const MAX_PART_RETRIES: u32 = 5;
 const POSTFIX_SIZES: [&str; 6] = ["B", "KB", "MB", "GB", "TB", "PB"];
 
 impl FileStatus {
@@ -33,37 +49,27 @@ mod version {
         }
     }
 
-    pub enum VersionCompatibility {
-        Compatible,
-        Incompatible,
-    }
-
-    pub fn compare_versions(
-        server_version: &Version,
-        client_version: &Version,
-    ) -> VersionCompatibility {
-        if server_version.major != client_version.major {
-            VersionCompatibility::Incompatible
-        } else if server_version.minor < client_version.minor {
-            VersionCompatibility::Incompatible
-        } else {
-            VersionCompatibility::Compatible
-        }
-    }
-
     impl Display for Version {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
         }
     }
 }
-use std::{io::Bytes, net::TcpStream};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
 
-use structs::{FileStatus, FileStatusEnum};
+pub use capabilities::*;
+pub use message::*;
+pub use rate_limit::*;
+use sha2::{Digest, Sha256};
+use structs::{ByteRange, FileStatus, FileStatusEnum};
 pub use version::*;
 
 mod file_description {
-    use crate::{structs::FileDescription, DEFAULT_PACKET_SIZE};
+    use crate::{DEFAULT_PACKET_SIZE, structs::FileDescription, update_hash};
+    use sha2::{Digest, Sha256};
     use std::path::PathBuf;
 
     impl TryFrom<&PathBuf> for FileDescription {
@@ -77,17 +83,28 @@ mod file_description {
                 },
                 None => Err(std::io::Error::other("Failed to get the filename"))?,
             };
-            let size = std::fs::File::open(value)?.metadata()?.len();
+            let file = std::fs::File::open(value)?;
+            let size = file.metadata()?.len();
+
+            let mut hasher = Sha256::new();
+            update_hash(&mut hasher, file)?;
 
             Ok(Self {
                 name: filename,
                 size,
                 packet_size: DEFAULT_PACKET_SIZE,
+                hash: hasher.finalize().to_vec(),
             })
         }
     }
 
     impl FileDescription {
+        /// The path to store the file under on the server, with `/` between directories
+        pub fn with_name(mut self, name: String) -> Self {
+            self.name = name;
+            self
+        }
+
         pub fn with_packet_size(mut self, packet_size: u64) -> Self {
             self.packet_size = packet_size;
             self
@@ -95,6 +112,797 @@ mod file_description {
     }
 }
 
+mod part_range {
+    use crate::structs::{ByteRange, PartRange};
+
+    impl PartRange {
+        /// Collapses sorted part numbers into as few ranges as possible
+        pub fn from_parts(parts: impl IntoIterator<Item = u64>) -> Vec<Self> {
+            let mut ranges: Vec<Self> = Vec::new();
+            for part in parts {
+                match ranges.last_mut() {
+                    Some(range) if range.end == part => range.end += 1,
+                    _ => ranges.push(Self {
+                        start: part,
+                        end: part + 1,
+                    }),
+                }
+            }
+            ranges
+        }
+    }
+
+    /// What ranges of parts and ranges of bytes have in common
+    macro_rules! ranges {
+        ($($range:ident),*) => {$(
+            impl $range {
+                pub fn len(&self) -> u64 {
+                    self.end - self.start
+                }
+
+                pub fn is_empty(&self) -> bool {
+                    self.end <= self.start
+                }
+
+                /// Sorts `ranges` and joins the ones that touch or overlap
+                pub fn merge(ranges: impl IntoIterator<Item = Self>) -> Vec<Self> {
+                    let mut ranges = ranges
+                        .into_iter()
+                        .filter(|range| !range.is_empty())
+                        .collect::<Vec<_>>();
+                    ranges.sort_by_key(|range| range.start);
+
+                    let mut merged: Vec<Self> = Vec::with_capacity(ranges.len());
+                    for range in ranges {
+                        match merged.last_mut() {
+                            Some(last) if range.start <= last.end => {
+                                last.end = last.end.max(range.end)
+                            }
+                            _ => merged.push(range),
+                        }
+                    }
+                    merged
+                }
+
+                /// Everything in `0..total` not covered by the sorted `ranges`
+                pub fn complement(ranges: &[Self], total: u64) -> Vec<Self> {
+                    let mut start = 0;
+                    let mut gaps = Vec::new();
+                    for range in ranges {
+                        if range.start > start {
+                            gaps.push(Self {
+                                start,
+                                end: range.start.min(total),
+                            });
+                        }
+                        start = start.max(range.end);
+                    }
+                    if start < total {
+                        gaps.push(Self { start, end: total });
+                    }
+                    gaps.retain(|range| !range.is_empty());
+                    gaps
+                }
+            }
+        )*};
+    }
+
+    ranges!(PartRange, ByteRange);
+}
+
+mod message {
+    use std::{
+        error::Error,
+        fmt::Display,
+        io::{self, Read, Write},
+        sync::mpsc::{self, RecvTimeoutError},
+        time::Duration,
+    };
+
+    use lazy_marshal::prelude::*;
+
+    use crate::{
+        MAX_MESSAGE_SIZE, StreamIterator,
+        structs::{
+            AuthRequest, AuthResponse, Busy, DeleteRequest, DownloadParts, DownloadRequest,
+            DownloadResponse, ErrorMessage, FileDescription, FileDescriptionResponse,
+            FileOpResponse, FilePart, FilePartResponse, Heartbeat, ListRequest, ListResponse,
+            MessageType, RenameRequest, ResumeDecision, UploadResult,
+        },
+    };
+
+    /// Anything that can be sent on its own in an [`Envelope`]
+    pub trait Message: Marshal + UnMarshal {
+        const TYPE: MessageType;
+    }
+
+    /// Gives each message type its [`MessageType`], and builds the mapping back from the wire
+    /// out of the same list. Types whose variant is named differently go after the `;`
+    macro_rules! messages {
+        ($($name:ident),* ; $($other:ident => $kind:ident),*) => {
+            $(impl Message for $name {
+                const TYPE: MessageType = MessageType::$name;
+            })*
+            $(impl Message for $other {
+                const TYPE: MessageType = MessageType::$kind;
+            })*
+
+            impl TryFrom<u32> for MessageType {
+                type Error = u32;
+
+                fn try_from(value: u32) -> Result<Self, u32> {
+                    Ok(match value {
+                        $(value if value == MessageType::$name as u32 => MessageType::$name,)*
+                        $(value if value == MessageType::$kind as u32 => MessageType::$kind,)*
+                        _ => Err(value)?,
+                    })
+                }
+            }
+
+            /// Stops the build when a variant is added without a message to go with it
+            const _: fn(MessageType) = |kind| match kind {
+                $(MessageType::$name)|* $(| MessageType::$kind)* => (),
+            };
+        };
+    }
+
+    messages!(
+        AuthRequest,
+        AuthResponse,
+        FileDescription,
+        FileDescriptionResponse,
+        ResumeDecision,
+        FilePart,
+        FilePartResponse,
+        UploadResult,
+        DownloadRequest,
+        DownloadResponse,
+        DownloadParts,
+        ListRequest,
+        ListResponse,
+        DeleteRequest,
+        RenameRequest,
+        FileOpResponse,
+        Heartbeat,
+        Busy;
+        ErrorMessage => Error
+    );
+
+    impl ErrorMessage {
+        /// The reply to a message that has no place where `envelope` turned up
+        pub fn unexpected(envelope: &Envelope) -> Self {
+            let message = match envelope.message_type() {
+                Some(kind) => format!("Unexpected {kind:?} message"),
+                None => format!("Unknown message type {}", envelope.kind),
+            };
+            Self { message }
+        }
+    }
+
+    impl Display for Busy {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}, retry after {}s", self.reason, self.retry_after)
+        }
+    }
+
+    impl Error for Busy {}
+
+    /// Bytes in front of every message, its type then the length of its payload
+    pub(crate) const HEADER_LEN: usize = 12;
+
+    /// One framed message whose payload hasn't been unmarshalled yet
+    pub struct Envelope {
+        pub kind: u32,
+        pub payload: Vec<u8>,
+    }
+
+    impl Envelope {
+        pub fn new<M: Message>(message: M) -> Self {
+            Self {
+                kind: M::TYPE as u32,
+                payload: message.marshal().collect(),
+            }
+        }
+
+        /// `None` when the peer sent a type we don't know about
+        pub fn message_type(&self) -> Option<MessageType> {
+            MessageType::try_from(self.kind).ok()
+        }
+
+        /// Unmarshals the payload as `M`. An [`ErrorMessage`] or [`Busy`] from the peer comes back as
+        /// the error
+        pub fn open<M: Message>(self) -> Result<M, Box<dyn Error>> {
+            match self.message_type() {
+                Some(kind) if kind == M::TYPE => Ok(M::unmarshal(&mut self.payload.into_iter())?),
+                Some(MessageType::Error) => {
+                    Err(ErrorMessage::unmarshal(&mut self.payload.into_iter())?.message)?
+                }
+                Some(MessageType::Busy) => Err(Busy::unmarshal(&mut self.payload.into_iter())?)?,
+                Some(kind) => Err(format!("Expected a {:?} message but got {kind:?}", M::TYPE))?,
+                None => Err(format!(
+                    "Expected a {:?} message but got unknown type {}",
+                    M::TYPE,
+                    self.kind
+                ))?,
+            }
+        }
+
+        /// The type and payload length in front of every message, checking the length is sane
+        pub(crate) fn parse_header(header: [u8; HEADER_LEN]) -> io::Result<(u32, u64)> {
+            let kind = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let len = u64::from_le_bytes(header[4..].try_into().unwrap());
+            if len > MAX_MESSAGE_SIZE {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Message too large ({len} > {MAX_MESSAGE_SIZE})"),
+                ))?
+            }
+            Ok((kind, len))
+        }
+
+        pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+            let mut header = [0; HEADER_LEN];
+            reader.read_exact(&mut header)?;
+            let (kind, len) = Self::parse_header(header)?;
+
+            // Grown as it's read so a bogus length can't make us allocate it all up front
+            let mut payload = Vec::new();
+            reader.take(len).read_to_end(&mut payload)?;
+            if payload.len() as u64 != len {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof))?
+            }
+            Ok(Self { kind, payload })
+        }
+
+        /// The message as it goes on the wire, header and all
+        pub(crate) fn into_bytes(self) -> Vec<u8> {
+            let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+            buf.extend(self.kind.to_le_bytes());
+            buf.extend((self.payload.len() as u64).to_le_bytes());
+            buf.extend(self.payload);
+            buf
+        }
+
+        pub fn write_to(self, writer: &mut impl Write) -> io::Result<()> {
+            writer.write_all(&self.into_bytes())
+        }
+    }
+
+    impl StreamIterator {
+        pub fn send<M: Message>(&mut self, message: M) -> io::Result<()> {
+            self.send_envelope(Envelope::new(message))
+        }
+
+        pub fn send_envelope(&mut self, envelope: Envelope) -> io::Result<()> {
+            envelope.write_to(self)
+        }
+
+        /// Reads the next message that isn't a [`Heartbeat`]. Running into the read timeout means
+        /// the peer sent nothing at all for that long, so it's most likely gone
+        pub fn recv_envelope(&mut self) -> io::Result<Envelope> {
+            loop {
+                let envelope = Envelope::read_from(self).map_err(|err| match err.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Heard nothing from the peer, not even a heartbeat, before the read timeout",
+                    ),
+                    _ => err,
+                })?;
+                if envelope.message_type() != Some(MessageType::Heartbeat) {
+                    return Ok(envelope);
+                }
+            }
+        }
+
+        /// Runs `work`, like disk I/O that can stall on a network drive, on another thread, sending
+        /// a [`Heartbeat`] every `interval` until it's done so the peer doesn't give up on us. A
+        /// zero `interval` means the peer doesn't understand them, so `work` just runs
+        pub fn with_heartbeats<T: Send>(
+            &mut self,
+            interval: Duration,
+            work: impl FnOnce() -> T + Send,
+        ) -> io::Result<T> {
+            if interval.is_zero() {
+                return Ok(work());
+            }
+
+            std::thread::scope(|scope| {
+                let (tx, rx) = mpsc::channel();
+                scope.spawn(move || tx.send(work()));
+                for seq in 0.. {
+                    match rx.recv_timeout(interval) {
+                        Ok(out) => return Ok(out),
+                        Err(RecvTimeoutError::Timeout) => self.send(Heartbeat { seq })?,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                Err(io::Error::other(
+                    "Work running alongside heartbeats panicked",
+                ))
+            })
+        }
+
+        /// Reads the next message, which has to be an `M`
+        pub fn recv<M: Message>(&mut self) -> Result<M, Box<dyn Error>> {
+            self.recv_envelope()?.open()
+        }
+    }
+}
+
+mod file_part {
+    use std::{
+        fs::File,
+        io::{self, Read, Seek, SeekFrom},
+    };
+
+    use crate::structs::{Checksum, Compression, FilePart};
+
+    impl FilePart {
+        /// Reads the part at `offset` out of `file`, as much as fits in `buf` or is left before
+        /// the end of the file
+        pub fn read(
+            file: &mut File,
+            buf: &mut [u8],
+            offset: u64,
+            compression: Compression,
+            checksum: Checksum,
+        ) -> io::Result<Self> {
+            file.seek(SeekFrom::Start(offset))?;
+            let mut r = 0;
+            while r < buf.len() {
+                match file.read(&mut buf[r..])? {
+                    0 => break,
+                    n => r += n,
+                }
+            }
+
+            // Already compressed media tends to come out bigger, so just send those as is
+            let (compression, data) = match compression.compress(&buf[..r])? {
+                data if data.len() < r => (compression, data),
+                _ => (Compression::None, buf[..r].to_vec()),
+            };
+            Ok(FilePart {
+                offset,
+                checksum: checksum.compute(&data),
+                compression,
+                data,
+            })
+        }
+    }
+}
+
+mod compression {
+    use std::{fmt::Display, io::Read, str::FromStr};
+
+    use crate::structs::Compression;
+
+    impl Compression {
+        pub fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+            match self {
+                Compression::None => Ok(data.to_vec()),
+                Compression::Zstd => zstd::encode_all(data, 0),
+                Compression::Deflate => {
+                    let mut encoder =
+                        flate2::read::DeflateEncoder::new(data, flate2::Compression::default());
+                    let mut out = Vec::new();
+                    encoder.read_to_end(&mut out)?;
+                    Ok(out)
+                }
+            }
+        }
+
+        /// Fails if the data would come out larger than `limit` so a bad peer can't blow up our memory
+        pub fn decompress(self, data: &[u8], limit: u64) -> std::io::Result<Vec<u8>> {
+            let mut out = Vec::new();
+            match self {
+                Compression::None => return Ok(data.to_vec()),
+                Compression::Zstd => zstd::Decoder::new(data)?
+                    .take(limit + 1)
+                    .read_to_end(&mut out)?,
+                Compression::Deflate => flate2::read::DeflateDecoder::new(data)
+                    .take(limit + 1)
+                    .read_to_end(&mut out)?,
+            };
+            if out.len() as u64 > limit {
+                Err(std::io::Error::other(format!(
+                    "Part decompresses to more than {limit} bytes"
+                )))?
+            }
+            Ok(out)
+        }
+    }
+
+    impl FromStr for Compression {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s.to_lowercase().as_str() {
+                "none" => Ok(Compression::None),
+                "zstd" => Ok(Compression::Zstd),
+                "deflate" => Ok(Compression::Deflate),
+                _ => Err(format!(
+                    "Unknown compression \"{s}\", expected one of: none, zstd, deflate"
+                )),
+            }
+        }
+    }
+
+    impl Display for Compression {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Compression::None => write!(f, "none"),
+                Compression::Zstd => write!(f, "zstd"),
+                Compression::Deflate => write!(f, "deflate"),
+            }
+        }
+    }
+}
+
+mod checksum {
+    use std::{fmt::Display, str::FromStr};
+
+    use crate::structs::Checksum;
+
+    impl Checksum {
+        pub fn compute(self, data: &[u8]) -> u64 {
+            match self {
+                Checksum::Crc32c => crc32c::crc32c(data) as u64,
+                Checksum::Xxh3 => xxhash_rust::xxh3::xxh3_64(data),
+            }
+        }
+    }
+
+    impl FromStr for Checksum {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s.to_lowercase().as_str() {
+                "crc32c" => Ok(Checksum::Crc32c),
+                "xxh3" => Ok(Checksum::Xxh3),
+                _ => Err(format!(
+                    "Unknown checksum \"{s}\", expected one of: crc32c, xxh3"
+                )),
+            }
+        }
+    }
+
+    impl Display for Checksum {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Checksum::Crc32c => write!(f, "crc32c"),
+                Checksum::Xxh3 => write!(f, "xxh3"),
+            }
+        }
+    }
+}
+
+mod capabilities {
+    use std::time::Duration;
+
+    use crate::{
+        MAX_PACKET_SIZE, MIN_PACKET_SIZE,
+        structs::{Capability, CapabilityKind, Checksum, Compression},
+    };
+
+    /// Every codec and algorithm, at the index that is their value on the wire
+    const COMPRESSIONS: [Compression; 3] =
+        [Compression::None, Compression::Zstd, Compression::Deflate];
+    const CHECKSUMS: [Checksum; 2] = [Checksum::Crc32c, Checksum::Xxh3];
+
+    impl TryFrom<u32> for CapabilityKind {
+        type Error = u32;
+
+        fn try_from(value: u32) -> Result<Self, u32> {
+            Ok(match value {
+                1 => CapabilityKind::Compression,
+                2 => CapabilityKind::Checksum,
+                3 => CapabilityKind::Window,
+                4 => CapabilityKind::MaxPacketSize,
+                5 => CapabilityKind::Heartbeat,
+                6 => CapabilityKind::MinPacketSize,
+                7 => CapabilityKind::Protocol,
+                _ => Err(value)?,
+            })
+        }
+    }
+
+    /// What a peer supports, or what two of them have in common
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Capabilities {
+        /// Most preferred first
+        pub compression: Vec<Compression>,
+        /// Most preferred first
+        pub checksum: Vec<Checksum>,
+        pub window: u64,
+        pub min_packet_size: u64,
+        pub max_packet_size: u64,
+        /// How often to send [`crate::structs::Heartbeat`]s while busy, in milliseconds. Zero
+        /// when they aren't understood
+        pub heartbeat_interval: u64,
+        /// Revisions of the wire protocol spoken, see [`crate::PROTOCOL_REVISION`]
+        pub protocol: Vec<u64>,
+    }
+
+    /// What a peer that doesn't mention a capability is taken to support
+    impl Default for Capabilities {
+        fn default() -> Self {
+            Self {
+                compression: vec![Compression::None],
+                checksum: vec![Checksum::Crc32c],
+                window: u64::MAX,
+                min_packet_size: MIN_PACKET_SIZE,
+                max_packet_size: MAX_PACKET_SIZE,
+                heartbeat_interval: 0,
+                // Peers from before revisions were exchanged speak the first one
+                protocol: vec![1],
+            }
+        }
+    }
+
+    fn encode<T: PartialEq>(all: &[T], values: &[T]) -> Vec<u64> {
+        values
+            .iter()
+            .filter_map(|value| all.iter().position(|known| known == value))
+            .map(|index| index as u64)
+            .collect()
+    }
+
+    /// Values from a newer peer that we don't know are dropped
+    fn decode<T: Copy>(all: &[T], values: &[u64]) -> Vec<T> {
+        values
+            .iter()
+            .filter_map(|&value| all.get(usize::try_from(value).ok()?).copied())
+            .collect()
+    }
+
+    impl Capabilities {
+        pub fn to_wire(&self) -> Vec<Capability> {
+            let capability = |kind: CapabilityKind, values| Capability {
+                kind: kind as u32,
+                values,
+            };
+            vec![
+                capability(
+                    CapabilityKind::Compression,
+                    encode(&COMPRESSIONS, &self.compression),
+                ),
+                capability(CapabilityKind::Checksum, encode(&CHECKSUMS, &self.checksum)),
+                capability(CapabilityKind::Window, vec![self.window]),
+                capability(CapabilityKind::MinPacketSize, vec![self.min_packet_size]),
+                capability(CapabilityKind::MaxPacketSize, vec![self.max_packet_size]),
+                capability(CapabilityKind::Heartbeat, vec![self.heartbeat_interval]),
+                capability(CapabilityKind::Protocol, self.protocol.clone()),
+            ]
+        }
+
+        pub fn from_wire(capabilities: &[Capability]) -> Self {
+            let mut out = Self::default();
+            for Capability { kind, values } in capabilities {
+                match CapabilityKind::try_from(*kind) {
+                    Ok(CapabilityKind::Compression) => {
+                        out.compression = decode(&COMPRESSIONS, values)
+                    }
+                    Ok(CapabilityKind::Checksum) => out.checksum = decode(&CHECKSUMS, values),
+                    Ok(CapabilityKind::Window) => {
+                        out.window = values.first().copied().unwrap_or(out.window)
+                    }
+                    Ok(CapabilityKind::MinPacketSize) => {
+                        out.min_packet_size = values.first().copied().unwrap_or(out.min_packet_size)
+                    }
+                    Ok(CapabilityKind::MaxPacketSize) => {
+                        out.max_packet_size = values.first().copied().unwrap_or(out.max_packet_size)
+                    }
+                    Ok(CapabilityKind::Heartbeat) => {
+                        out.heartbeat_interval =
+                            values.first().copied().unwrap_or(out.heartbeat_interval)
+                    }
+                    Ok(CapabilityKind::Protocol) => out.protocol = values.clone(),
+                    // Something a newer peer supports that we don't
+                    Err(_) => (),
+                }
+            }
+            out
+        }
+
+        /// What both `self` and `other` support, in the order `other` prefers
+        pub fn intersect(&self, other: &Self) -> Self {
+            Self {
+                compression: other
+                    .compression
+                    .iter()
+                    .filter(|codec| self.compression.contains(codec))
+                    .copied()
+                    .collect(),
+                checksum: other
+                    .checksum
+                    .iter()
+                    .filter(|algorithm| self.checksum.contains(algorithm))
+                    .copied()
+                    .collect(),
+                window: self.window.min(other.window),
+                min_packet_size: self.min_packet_size.max(other.min_packet_size),
+                max_packet_size: self.max_packet_size.min(other.max_packet_size),
+                heartbeat_interval: self.heartbeat_interval.min(other.heartbeat_interval),
+                protocol: other
+                    .protocol
+                    .iter()
+                    .filter(|revision| self.protocol.contains(revision))
+                    .copied()
+                    .collect(),
+            }
+        }
+
+        /// What the server (`self`) and a client can both use, or why they can't talk at all.
+        /// Nothing can be read without a protocol revision both sides speak, and parts can't be
+        /// sent without a checksum both sides know. Everything else has a fallback
+        pub fn agree(&self, client: &Self) -> Result<Self, String> {
+            fn names<T: ToString>(values: &[T]) -> String {
+                values
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+
+            let agreed = self.intersect(client);
+            if agreed.protocol.is_empty() {
+                Err(format!(
+                    "No common protocol revision, the server speaks [{}] and the client [{}]",
+                    names(&self.protocol),
+                    names(&client.protocol)
+                ))?
+            }
+            if agreed.checksum.is_empty() {
+                Err(format!(
+                    "No common checksum, the server allows [{}] and the client [{}]",
+                    names(&self.checksum),
+                    names(&client.checksum)
+                ))?
+            }
+            Ok(agreed)
+        }
+
+        /// Whether parts of `packet_size` are within the bounds both sides accept
+        pub fn packet_size_ok(&self, packet_size: u64) -> bool {
+            (self.min_packet_size..=self.max_packet_size).contains(&packet_size)
+        }
+
+        pub fn heartbeat_interval(&self) -> Duration {
+            Duration::from_millis(self.heartbeat_interval)
+        }
+
+        /// The codec to send parts with, sending them as is always works
+        pub fn compression(&self) -> Compression {
+            self.compression
+                .first()
+                .copied()
+                .unwrap_or(Compression::None)
+        }
+
+        /// The algorithm to check parts with. Only capabilities from [`Self::agree`] are sure to
+        /// have one
+        pub fn checksum(&self) -> Checksum {
+            self.checksum[0]
+        }
+    }
+}
+
+mod rate_limit {
+    use std::{
+        io,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use crate::{POSTFIX_SIZES, StreamIterator, file_size_text};
+
+    /// Token bucket that everything counting against the same limit takes from
+    #[derive(Debug)]
+    pub struct RateLimiter {
+        /// Bytes a second, `None` when there's no limit
+        rate: Option<u64>,
+        bucket: Mutex<Bucket>,
+    }
+
+    #[derive(Debug)]
+    struct Bucket {
+        /// Goes negative when a take is bigger than what's there
+        tokens: f64,
+        refilled: Instant,
+    }
+
+    impl RateLimiter {
+        pub fn new(rate: Option<u64>) -> Self {
+            Self {
+                rate,
+                bucket: Mutex::new(Bucket {
+                    tokens: rate.unwrap_or_default() as f64,
+                    refilled: Instant::now(),
+                }),
+            }
+        }
+
+        pub fn unlimited() -> Self {
+            Self::new(None)
+        }
+
+        pub fn rate(&self) -> Option<u64> {
+            self.rate
+        }
+
+        /// How long to wait before `bytes` more fit under the rate. Up to a second's worth can
+        /// go in a burst. Bigger takes put the bucket in debt, so the average still holds
+        pub fn take(&self, bytes: u64) -> Duration {
+            let Some(rate) = self.rate else {
+                return Duration::ZERO;
+            };
+            let rate = rate as f64;
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+            bucket.refilled = now;
+            Duration::from_secs_f64((-bucket.tokens).max(0.0) / rate)
+        }
+    }
+
+    impl StreamIterator {
+        /// Waits until `bytes` fit under every one of `limiters`, sending heartbeats meanwhile so
+        /// the peer doesn't take a long wait for a dead connection
+        pub fn throttle(
+            &mut self,
+            limiters: &[&RateLimiter],
+            bytes: u64,
+            heartbeat: Duration,
+        ) -> io::Result<()> {
+            let wait = limiters
+                .iter()
+                .map(|limiter| limiter.take(bytes))
+                .max()
+                .unwrap_or_default();
+            match wait.is_zero() {
+                true => Ok(()),
+                false => self.with_heartbeats(heartbeat, || std::thread::sleep(wait)),
+            }
+        }
+    }
+
+    /// Parses a rate like `20MB/s`, `512KB` or `1.5 GB/s` into bytes a second, using the same
+    /// units as [`file_size_text`]
+    pub fn parse_rate(text: &str) -> Result<u64, String> {
+        let invalid = || format!("\"{text}\" isn't a rate like 20MB/s");
+        let trimmed = text.trim();
+        let trimmed = match trimmed.len().checked_sub(2) {
+            Some(end) if trimmed[end..].eq_ignore_ascii_case("/s") => &trimmed[..end],
+            _ => trimmed,
+        };
+        let split = trimmed
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(split);
+        let number = number.parse::<f64>().map_err(|_| invalid())?;
+        let unit = unit.trim();
+        let power = match unit.is_empty() {
+            true => 0,
+            false => POSTFIX_SIZES
+                .iter()
+                .position(|postfix| postfix.eq_ignore_ascii_case(unit))
+                .ok_or_else(invalid)?,
+        };
+
+        let rate = number * 2f64.powi(power as i32 * 10);
+        if !rate.is_finite() || rate < 1.0 || rate > u64::MAX as f64 {
+            Err(format!("\"{text}\" has to be at least a byte a second"))?
+        }
+        Ok(rate as u64)
+    }
+
+    /// A rate in bytes a second, the way [`file_size_text`] shows sizes
+    pub fn rate_text(rate: u64) -> String {
+        format!("{}/s", file_size_text(rate))
+    }
+}
+
 pub fn num_packets(packet_size: u64, file_size: u64) -> u64 {
     (file_size as f64 / packet_size as f64).ceil() as u64
 }
@@ -104,7 +912,7 @@ pub fn file_size_text(file_size: u64) -> String {
         .iter()
         .enumerate()
         .reduce(
-            |acc, (i, val)| match 1 > 2_i64.pow(i as u32 * 10) - file_size as i64 {
+            |acc, (i, val)| match file_size >= 2_u64.pow(i as u32 * 10) {
                 true => (i, val),
                 false => acc,
             },
@@ -117,12 +925,343 @@ pub fn file_size_text(file_size: u64) -> String {
     )
 }
 
-pub struct StreamIterator(pub Bytes<TcpStream>);
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The inverse of [`to_hex`], `None` if `hex` isn't valid
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Feeds everything left in `reader` into `hasher`
+pub fn update_hash(hasher: &mut Sha256, mut reader: impl Read) -> std::io::Result<()> {
+    let mut buf = vec![0; DEFAULT_PACKET_SIZE as usize];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(()),
+            r => hasher.update(&buf[..r]),
+        }
+    }
+}
+
+/// Feeds the bytes covered by `ranges` into `hasher`, in order
+pub fn hash_ranges(
+    hasher: &mut Sha256,
+    file: &mut File,
+    ranges: &[ByteRange],
+) -> std::io::Result<()> {
+    for range in ranges {
+        file.seek(SeekFrom::Start(range.start))?;
+        update_hash(hasher, (&mut *file).take(range.len()))?;
+    }
+    Ok(())
+}
+
+/// Anything the protocol can run over, like a plain `TcpStream` or a TLS stream wrapping one
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Both ends of a connection. Iterating reads it a byte at a time for unmarshalling
+pub struct StreamIterator(pub Box<dyn Stream>);
 
 impl Iterator for StreamIterator {
     type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
-        Some(unsafe { self.0.next()?.unwrap_unchecked() })
+        let mut byte = [0];
+        self.0.read_exact(&mut byte).ok()?;
+        Some(byte[0])
+    }
+}
+
+impl Read for StreamIterator {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for StreamIterator {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    use crate::{
+        Capabilities, Envelope, HEADER_LEN, MAX_MESSAGE_SIZE, RateLimiter, StreamIterator,
+        parse_rate, rate_text,
+        structs::{
+            ByteRange, Capability, Checksum, Compression, DeleteRequest, ErrorMessage, MessageType,
+            PartRange, RenameRequest,
+        },
+    };
+
+    fn range(start: u64, end: u64) -> PartRange {
+        PartRange { start, end }
+    }
+
+    #[test]
+    fn part_ranges() {
+        let received = PartRange::from_parts([0, 1, 2, 5, 7, 8]);
+        assert_eq!(received, vec![range(0, 3), range(5, 6), range(7, 9)]);
+        assert_eq!(
+            PartRange::complement(&received, 12),
+            vec![range(3, 5), range(6, 7), range(9, 12)]
+        );
+        assert_eq!(PartRange::complement(&[], 4), vec![range(0, 4)]);
+        assert_eq!(PartRange::complement(&[range(0, 4)], 4), vec![]);
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let range = |start, end| ByteRange { start, end };
+        assert_eq!(
+            ByteRange::merge([
+                range(10, 20),
+                range(0, 5),
+                range(5, 8),
+                range(15, 30),
+                range(9, 9)
+            ]),
+            vec![range(0, 8), range(10, 30)]
+        );
+        assert_eq!(
+            ByteRange::complement(&[range(2, 5)], 8),
+            vec![range(0, 2), range(5, 8)]
+        );
+    }
+
+    #[test]
+    fn capabilities() {
+        let server = Capabilities {
+            compression: vec![Compression::Zstd, Compression::None],
+            checksum: vec![Checksum::Crc32c, Checksum::Xxh3],
+            window: 64,
+            min_packet_size: 2u64.pow(21),
+            max_packet_size: 2u64.pow(24),
+            heartbeat_interval: 10_000,
+            protocol: vec![1],
+        };
+        let client = Capabilities {
+            compression: vec![Compression::Deflate, Compression::Zstd],
+            checksum: vec![Checksum::Xxh3, Checksum::Crc32c],
+            window: 8,
+            min_packet_size: 2u64.pow(20),
+            max_packet_size: 2u64.pow(30),
+            heartbeat_interval: 5_000,
+            protocol: vec![1],
+        };
+
+        let mut wire = client.to_wire();
+        wire.push(Capability {
+            kind: 99,
+            values: vec![1, 2, 3],
+        });
+        wire[0].values.push(42);
+        assert_eq!(Capabilities::from_wire(&wire), client);
+
+        let agreed = server.intersect(&client);
+        assert_eq!(agreed.compression(), Compression::Zstd);
+        assert_eq!(agreed.checksum(), Checksum::Xxh3);
+        assert_eq!((agreed.window, agreed.max_packet_size), (8, 2u64.pow(24)));
+        assert!(agreed.packet_size_ok(2u64.pow(21)) && !agreed.packet_size_ok(2u64.pow(20)));
+        assert_eq!(agreed.heartbeat_interval, 5_000);
+        assert_eq!(Capabilities::from_wire(&agreed.to_wire()), agreed);
+
+        // A peer that says nothing still gets the basics
+        let old = Capabilities::from_wire(&[]).intersect(&client);
+        assert_eq!(old.compression(), Compression::None);
+        assert_eq!(old.checksum(), Checksum::Crc32c);
+        assert_eq!(old.heartbeat_interval, 0);
+
+        let xxh3_only = Capabilities {
+            checksum: vec![Checksum::Xxh3],
+            ..Capabilities::default()
+        };
+        assert_eq!(xxh3_only.agree(&client).unwrap().checksum(), Checksum::Xxh3);
+        assert_eq!(
+            xxh3_only.agree(&Capabilities::default()),
+            Err("No common checksum, the server allows [xxh3] and the client [crc32c]".to_string())
+        );
+    }
+
+    #[test]
+    fn protocol_revisions() {
+        let speaking = |protocol: &[u64]| Capabilities {
+            protocol: protocol.to_vec(),
+            ..Capabilities::default()
+        };
+        let agreed = speaking(&[1, 2]).agree(&speaking(&[2, 3])).unwrap();
+        assert_eq!(agreed.protocol, vec![2]);
+        // A peer that doesn't say speaks the first
+        let old = Capabilities::from_wire(&[]);
+        assert_eq!(speaking(&[1, 2]).agree(&old).unwrap().protocol, vec![1]);
+        assert_eq!(
+            speaking(&[2]).agree(&old),
+            Err(
+                "No common protocol revision, the server speaks [2] and the client [1]".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("20MB/s"), Ok(20 * 2u64.pow(20)));
+        assert_eq!(parse_rate("512kb"), Ok(512 * 2u64.pow(10)));
+        assert_eq!(parse_rate(" 1.5 GB/S "), Ok(3 * 2u64.pow(29)));
+        assert_eq!(parse_rate("100"), Ok(100));
+        for invalid in ["", "MB/s", "20XB/s", "-5MB", "0.1B/s", "1e9"] {
+            assert!(parse_rate(invalid).is_err(), "{invalid:?} parsed");
+        }
+        assert_eq!(rate_text(20 * 2u64.pow(20)), "20.00 MB/s");
+    }
+
+    #[test]
+    fn rate_limiter() {
+        assert_eq!(RateLimiter::unlimited().take(u64::MAX), Duration::ZERO);
+
+        // A second's worth goes straight away, anything past it has to wait
+        let limiter = RateLimiter::new(Some(1000));
+        assert_eq!(limiter.take(1000), Duration::ZERO);
+        let wait = limiter.take(2000);
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn envelopes() {
+        let bytes = Envelope::new(RenameRequest {
+            from: "a.txt".to_string(),
+            to: "b.txt".to_string(),
+        })
+        .into_bytes();
+
+        let envelope = Envelope::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(envelope.message_type(), Some(MessageType::RenameRequest));
+        let request = envelope.open::<RenameRequest>().unwrap();
+        assert_eq!(
+            (request.from.as_str(), request.to.as_str()),
+            ("a.txt", "b.txt")
+        );
+
+        // Cut short, so the payload never arrives
+        let short = Envelope::read_from(&mut &bytes[..bytes.len() - 1]);
+        assert_eq!(short.err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+
+        for kind in 1..=MessageType::Busy as u32 {
+            assert_eq!(MessageType::try_from(kind).unwrap() as u32, kind);
+        }
+        assert_eq!(MessageType::try_from(0), Err(0));
+    }
+
+    #[test]
+    fn unknown_envelopes() {
+        let unknown = Envelope {
+            kind: 1000,
+            payload: vec![1, 2, 3],
+        };
+        assert_eq!(unknown.message_type(), None);
+        assert_eq!(
+            ErrorMessage::unexpected(&unknown).message,
+            "Unknown message type 1000"
+        );
+        let unexpected = Envelope::new(ErrorMessage::unexpected(&Envelope::new(RenameRequest {
+            from: String::new(),
+            to: String::new(),
+        })));
+        assert_eq!(
+            unexpected
+                .open::<RenameRequest>()
+                .err()
+                .unwrap()
+                .to_string(),
+            "Unexpected RenameRequest message"
+        );
+        assert!(unknown.open::<RenameRequest>().is_err());
+    }
+
+    #[test]
+    fn oversized_envelopes() {
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&(MessageType::FilePart as u32).to_le_bytes());
+        header[4..].copy_from_slice(&MAX_MESSAGE_SIZE.to_le_bytes());
+        assert!(
+            Envelope::read_from(&mut header.as_slice())
+                .is_err_and(|err| err.kind() == io::ErrorKind::UnexpectedEof)
+        );
+
+        header[4..].copy_from_slice(&(MAX_MESSAGE_SIZE + 1).to_le_bytes());
+        let err = Envelope::read_from(&mut header.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Two ends of a loopback connection, the second one giving up after `timeout` of silence
+    fn socket_pair(timeout: Duration) -> (StreamIterator, StreamIterator) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (receiver, _) = listener.accept().unwrap();
+        receiver.set_read_timeout(Some(timeout)).unwrap();
+        (
+            StreamIterator(Box::new(sender)),
+            StreamIterator(Box::new(receiver)),
+        )
+    }
+
+    #[test]
+    fn heartbeats_outlast_the_read_timeout() {
+        let slow = || {
+            std::thread::sleep(Duration::from_millis(500));
+            "done"
+        };
+        let request = || DeleteRequest {
+            name: "a.txt".to_string(),
+        };
+
+        let (mut sender, mut receiver) = socket_pair(Duration::from_millis(200));
+        let receiving = std::thread::spawn(move || {
+            receiver
+                .recv::<DeleteRequest>()
+                .map(|req| req.name)
+                .map_err(|err| err.to_string())
+        });
+        assert_eq!(
+            sender
+                .with_heartbeats(Duration::from_millis(50), slow)
+                .unwrap(),
+            "done"
+        );
+        sender.send(request()).unwrap();
+        assert_eq!(receiving.join().unwrap().unwrap(), "a.txt");
+
+        // Without them the same wait looks like the sender is gone
+        let (mut sender, mut receiver) = socket_pair(Duration::from_millis(200));
+        let receiving = std::thread::spawn(move || receiver.recv_envelope().map(|_| ()));
+        assert_eq!(
+            sender.with_heartbeats(Duration::ZERO, slow).unwrap(),
+            "done"
+        );
+        let _ = sender.send(request());
+        assert_eq!(
+            receiving.join().unwrap().unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
     }
 }
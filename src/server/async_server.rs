@@ -0,0 +1,329 @@
+use std::{
+    error::Error,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rusqlite::Connection;
+use rustls::ServerConfig;
+use stable_ftp::{
+    Capabilities, Envelope, RateLimiter,
+    async_stream::AsyncStream,
+    db,
+    logger::{self, Loggable},
+    structs::{AuthRequest, Busy},
+    tls,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{
+    REJECT_TIMEOUT, RateLimits, Request, Timeouts, auth_failure, auth_success, authenticate,
+    log_session_end,
+    pool::Admission,
+    session::{DownloadSession, Exchange, Next, Step, UploadSession, throttled_bytes},
+    shutdown,
+};
+
+/// Errors that can be held across an await
+type AsyncResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// A session's read only connection, shared with the blocking work it hands off
+type ReadConn = Arc<Mutex<Connection>>;
+
+/// Everything sessions need from the server's arguments
+pub struct Server {
+    pub target_folder: PathBuf,
+    pub capabilities: Capabilities,
+    pub timeouts: Timeouts,
+    pub limits: RateLimits,
+    pub tls: Option<Arc<ServerConfig>>,
+    pub admission: Admission,
+    pub busy_retry_after: u64,
+}
+
+/// Serves every connection as a task on one event loop. A session that's waiting on a slow client
+/// costs some memory rather than a thread, while database and file work runs on at most
+/// `blocking_threads` threads. Returns after a shutdown, with whether every session ended within
+/// `shutdown_timeout`
+pub fn run(
+    server: Server,
+    addrs: Vec<SocketAddr>,
+    blocking_threads: usize,
+    shutdown_timeout: Duration,
+) -> io::Result<bool> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(blocking_threads)
+        .enable_all()
+        .build()?;
+    let server = Arc::new(server);
+    runtime.block_on(async {
+        let mut listeners = Vec::new();
+        for addr in addrs {
+            let listener = TcpListener::bind(addr)
+                .await
+                .to_error("Failed to bind to IP");
+            logger::info(format!("Server listening on {addr}"));
+            listeners.push(tokio::spawn(accept(listener, addr, server.clone())));
+        }
+        for listener in listeners {
+            let _ = listener.await;
+        }
+    });
+    // Sessions keep running on the runtime's threads while we wait on them here
+    let all_closed = server.admission.wait_closed(shutdown_timeout);
+    runtime.shutdown_background();
+    Ok(all_closed)
+}
+
+async fn accept(listener: TcpListener, addr: SocketAddr, server: Arc<Server>) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = shutdown::stopping() => break,
+        };
+        let Ok((tcp, peer)) = accepted.with_warning("Failed to connect") else {
+            continue;
+        };
+        match server.admission.admit(peer.ip()) {
+            Ok(slot) => {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    // The slot goes with the session, so it's freed once the session ends
+                    let _slot = slot;
+                    handle_client(tcp, peer, &server).await
+                });
+            }
+            Err(reason) => {
+                logger::warning(format!("Turning away {}: {reason}", peer.ip()));
+                tokio::spawn(send_busy(
+                    tcp,
+                    server.tls.clone(),
+                    server.busy_retry_after,
+                    reason,
+                ));
+            }
+        }
+    }
+    logger::info(format!("Stopped listening on {addr}"));
+}
+
+/// [`crate::send_busy`] for the event loop
+async fn send_busy(
+    tcp: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    retry_after: u64,
+    reason: String,
+) {
+    let reply = async move {
+        let mut stream = match tls {
+            Some(config) => match tls::accept_async(config, tcp).await {
+                Ok(stream) => stream,
+                Err(_) => return,
+            },
+            None => AsyncStream::new(tcp),
+        };
+        if stream.recv::<AuthRequest>().await.is_ok() {
+            let _ = stream
+                .send(Busy {
+                    retry_after,
+                    reason,
+                })
+                .await;
+        }
+    };
+    let _ = tokio::time::timeout(REJECT_TIMEOUT, reply).await;
+}
+
+/// [`crate::say_goodbye`] for the event loop
+async fn say_goodbye(stream: &mut AsyncStream, peer: SocketAddr) {
+    logger::info(format!("Telling {peer} the server is shutting down"));
+    if stream.send(shutdown::busy()).await.is_ok() {
+        stream.hang_up(REJECT_TIMEOUT).await;
+    }
+}
+
+async fn handle_client(tcp: TcpStream, peer: SocketAddr, server: &Server) {
+    let Server {
+        target_folder,
+        capabilities,
+        timeouts,
+        limits,
+        tls,
+        ..
+    } = server;
+    let connection_limiter = RateLimiter::new(limits.per_connection);
+    let limiters = [&*limits.global, &connection_limiter];
+    logger::info(format!("New client connected: {peer}"));
+
+    let mut stream = match tls {
+        Some(config) => {
+            let handshake = tls::accept_async(config.clone(), tcp);
+            match tokio::time::timeout(timeouts.read, handshake).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    logger::warning(format!("TLS handshake with {peer} failed: {err}"));
+                    return;
+                }
+                Err(_) => {
+                    logger::warning(format!("TLS handshake with {peer} timed out"));
+                    return;
+                }
+            }
+        }
+        None => AsyncStream::new(tcp),
+    };
+    stream.set_read_timeout(Some(timeouts.read));
+
+    let request = stream.recv::<AuthRequest>().await;
+    let capabilities = capabilities.clone();
+    let lookup = stream.blocking(Duration::ZERO, move || {
+        let read_conn =
+            db::get_read_connection().to_error("Failed to get read only connection to db");
+        let authenticated = match request {
+            Ok(request) => authenticate(&read_conn, &capabilities, request),
+            Err(err) => Err(auth_failure(format!("Auth request not understood: {err}"))),
+        };
+        (read_conn, authenticated)
+    });
+    let Ok((read_conn, authenticated)) = lookup.await else {
+        return;
+    };
+    let read_conn: ReadConn = Arc::new(Mutex::new(read_conn));
+    let (user_id, agreed) = match authenticated {
+        Ok(authenticated) => authenticated,
+        Err(response) => {
+            stream
+                .send(response)
+                .await
+                .to_error("Failed to write fail to stream");
+            return;
+        }
+    };
+    let heartbeat = agreed.heartbeat_interval();
+
+    if shutdown::requested() {
+        return say_goodbye(&mut stream, peer).await;
+    }
+    stream
+        .send(auth_success(&agreed))
+        .await
+        .to_error("Failed to return success auth message");
+
+    // Authenticated, so serve whatever the client asks for until it hangs up
+    loop {
+        if shutdown::requested() {
+            return say_goodbye(&mut stream, peer).await;
+        }
+        stream.set_read_timeout(Some(timeouts.idle));
+        let next = tokio::select! {
+            next = stream.recv_envelope() => next,
+            () = shutdown::stopping() => return say_goodbye(&mut stream, peer).await,
+        };
+        let envelope = match next {
+            Ok(envelope) => envelope,
+            Err(err) => return log_session_end(peer, timeouts.idle, err),
+        };
+        stream.set_read_timeout(Some(timeouts.read));
+
+        let handled = match Request::route(envelope) {
+            Request::Upload(envelope) => {
+                let upload = UploadSession::new(user_id, target_folder.clone(), agreed.clone());
+                exchange(
+                    &mut stream,
+                    &read_conn,
+                    heartbeat,
+                    &limiters,
+                    upload,
+                    envelope,
+                )
+                .await
+            }
+            Request::Download(envelope) => {
+                let download = DownloadSession::new(target_folder.clone(), agreed.clone());
+                exchange(
+                    &mut stream,
+                    &read_conn,
+                    heartbeat,
+                    &limiters,
+                    download,
+                    envelope,
+                )
+                .await
+            }
+            Request::Reply(reply, envelope) => {
+                let (read_conn, target_folder) = (read_conn.clone(), target_folder.clone());
+                let answer = stream.blocking(heartbeat, move || {
+                    reply.answer(
+                        &read_conn.lock().unwrap(),
+                        user_id,
+                        &target_folder,
+                        envelope,
+                    )
+                });
+                match answer.await {
+                    Ok(answer) => stream.send_envelope(answer).await.map_err(Into::into),
+                    Err(err) => Err(err.into()),
+                }
+            }
+            Request::Unexpected(reply) => {
+                logger::warning(format!("{} from {peer}", reply.message));
+                stream
+                    .send(reply)
+                    .await
+                    .to_error("Failed to write to stream");
+                continue;
+            }
+        };
+
+        if let Err(err) = handled {
+            logger::warning(format!("Ending the session with {peer}: {err}"));
+            return;
+        }
+    }
+}
+
+/// [`crate::exchange`] for the event loop. The exchange goes along with each step and comes back
+/// once it's taken
+async fn exchange<E: Exchange>(
+    stream: &mut AsyncStream,
+    read_conn: &ReadConn,
+    heartbeat: Duration,
+    limiters: &[&RateLimiter],
+    mut session: E,
+    envelope: Envelope,
+) -> AsyncResult<()> {
+    let mut received = Some(envelope);
+    loop {
+        let read_conn = read_conn.clone();
+        let step;
+        (session, step) = stream
+            .blocking(heartbeat, move || {
+                let step = session.step(&read_conn.lock().unwrap(), received);
+                (session, step)
+            })
+            .await?;
+        let Step { replies, next } = step;
+        for reply in replies {
+            if let Some(bytes) = throttled_bytes(&reply) {
+                stream.throttle(limiters, bytes, heartbeat).await?;
+            }
+            stream.send_envelope(reply).await?;
+        }
+
+        received = match next {
+            Next::Recv => {
+                let envelope = stream.recv_envelope().await?;
+                if let Some(bytes) = throttled_bytes(&envelope) {
+                    stream.throttle(limiters, bytes, heartbeat).await?;
+                }
+                Some(envelope)
+            }
+            Next::Send => None,
+            Next::Done => return Ok(()),
+            Next::Fail(err) => Err(err)?,
+        };
+    }
+}
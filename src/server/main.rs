@@ -1,170 +1,783 @@
 use std::{
+    collections::HashMap,
     error::Error,
     io::{self, prelude::*},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    net::{self, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
 use clap::Parser;
-use lazy_marshal::prelude::*;
-use rusqlite::Connection;
+use rusqlite::{Connection, params};
+use rustls::ServerConfig;
+use sha2::{Digest, Sha256};
 
 use stable_ftp::{
-    MIN_PACKET_SIZE, StreamIterator, VersionCompatibility, compare_versions,
-    db::{self, DbFile, UserAuth, get_write_connection},
-    file_size_text,
+    Capabilities, DEFAULT_PACKET_SIZE, Envelope, HEARTBEATS_PER_TIMEOUT, MAX_PACKET_SIZE,
+    MAX_PART_RETRIES, MAX_WINDOW, MIN_PACKET_SIZE, PROTOCOL_REVISION, RateLimiter, StreamIterator,
+    db::{self, DbFile, UnstagedUpload, UserAuth, UserQuota, get_write_connection},
+    file_size_text, from_hex, hash_ranges,
     logger::{self, Loggable},
-    num_packets,
+    parse_rate, rate_text,
     structs::{
-        AuthRequest, AuthResponse, FileDescription, FileDescriptionResponse, FilePartResponse,
-        FileStatus, FileStatusEnum, Id,
+        AuthRequest, AuthResponse, Busy, ByteRange, Checksum, Compression, DeleteRequest,
+        DownloadRequest, ErrorMessage, FileDescription, FileOpResponse, FilePart, FileStatus,
+        FileStatusEnum, Id, ListEntry, ListFilter, ListRequest, ListResponse, MessageType,
+        RenameRequest, UploadResult, UploadState,
     },
+    tls, to_hex, update_hash,
 };
 use typed_db::DbTable;
 
-fn handle_auth_err(stream: &mut TcpStream, msg: impl AsRef<str>) {
-    let response = AuthResponse {
+mod async_server;
+mod pool;
+mod sanitize;
+mod session;
+mod shutdown;
+
+use pool::{Admission, ConnectionLimits, Pool};
+use session::{DownloadSession, Exchange, Next, Step, UploadSession, throttled_bytes};
+
+/// How long a connection that's being turned away gets to say hello and hear why
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often listeners check for a shutdown while nobody is connecting
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+/// Turns a client away before its session starts, saying why
+fn auth_failure(reason: impl AsRef<str>) -> AuthResponse {
+    AuthResponse {
         success: false,
-        failure_reason: format!("Failed to Authenitcate: {}", msg.as_ref()),
+        failure_reason: format!("Failed to authenticate: {}", reason.as_ref()),
+        capabilities: Vec::new(),
+    }
+}
+
+/// Decides whether a client gets a session, the same whichever runtime serves it. Gives the user
+/// and what both sides agreed on, or the reply that turns the client away
+fn authenticate(
+    read_conn: &Connection,
+    capabilities: &Capabilities,
+    request: AuthRequest,
+) -> Result<(Id, Capabilities), AuthResponse> {
+    let AuthRequest {
+        version,
+        token,
+        capabilities: client_capabilities,
+    } = request;
+
+    let user = UserAuth::from_token(read_conn, &token).to_error("Failed to query user auth table");
+    let Some(user) = user else {
+        return Err(auth_failure("Invalid Token/Token Not Found"));
     };
-    stream
-        .write(&response.marshal().collect::<Vec<_>>())
-        .to_error("Failed to write to buffer stream");
-    return;
+
+    // Both sides go with the first of each that the client asked for and we also allow. Versions
+    // don't matter beyond that, so older and newer clients get by on what they have in common
+    let agreed = capabilities
+        .agree(&Capabilities::from_wire(&client_capabilities))
+        .map_err(|reason| {
+            auth_failure(format!(
+                "{reason} (client version {version}, server version {})",
+                env!("CARGO_PKG_VERSION")
+            ))
+        })?;
+    Ok((user.id, agreed))
 }
 
-fn handle_client(mut stream: TcpStream, target_folder: &Path) {
-    logger::info(format!(
-        "New client connected: {}",
-        stream.peer_addr().to_error("Can't get the peer address??")
-    ));
+fn auth_success(agreed: &Capabilities) -> AuthResponse {
+    AuthResponse {
+        success: true,
+        failure_reason: String::new(),
+        capabilities: agreed.to_wire(),
+    }
+}
 
-    let mut response_stream = StreamIterator(stream.try_clone().unwrap().bytes());
+/// What a client asked for once it's authenticated, sorted out the same whichever runtime
+/// serves it
+enum Request {
+    /// Starts with a [`FileDescription`], see [`UploadSession`]
+    Upload(Envelope),
+    /// See [`DownloadSession`]
+    Download(Envelope),
+    /// Answered with a single message, see [`Reply::answer`]
+    Reply(Reply, Envelope),
+    /// Nothing a client can ask for, answered with this and otherwise ignored
+    Unexpected(ErrorMessage),
+}
 
-    let AuthRequest { version, token } = match AuthRequest::unmarshal(&mut response_stream) {
-        Ok(req) => req,
-        Err(err) => {
-            handle_auth_err(&mut stream, format!("Auth request not understood: {err}"));
-            return;
+enum Reply {
+    List,
+    Delete,
+    Rename,
+}
+
+impl Request {
+    fn route(envelope: Envelope) -> Self {
+        match envelope.message_type() {
+            Some(MessageType::FileDescription) => Self::Upload(envelope),
+            Some(MessageType::DownloadRequest) => Self::Download(envelope),
+            Some(MessageType::ListRequest) => Self::Reply(Reply::List, envelope),
+            Some(MessageType::DeleteRequest) => Self::Reply(Reply::Delete, envelope),
+            Some(MessageType::RenameRequest) => Self::Reply(Reply::Rename, envelope),
+            _ => Self::Unexpected(ErrorMessage::unexpected(&envelope)),
         }
-    };
+    }
+}
 
-    // Verify Versions are compatible
-    let server_version = env!("CARGO_PKG_VERSION").into();
-    let client_version = &version;
-    if let VersionCompatibility::Incompatible = compare_versions(&server_version, client_version) {
-        handle_auth_err(
-            &mut stream,
-            format!(
-                "Version types are incompatible! Client version ({client_version}) is not compatible with server version ({server_version})"
-            ),
-        );
-        return;
+impl Reply {
+    /// Works out the reply to `envelope`. The write lock is only held in here
+    fn answer(
+        self,
+        read_conn: &Connection,
+        user_id: Id,
+        target_folder: &Path,
+        envelope: Envelope,
+    ) -> Envelope {
+        match self {
+            Reply::List => Envelope::new(list_response(read_conn, envelope)),
+            Reply::Delete => Envelope::new(file_op_response(delete_file(
+                &mut get_write_connection().lock().unwrap(),
+                user_id,
+                target_folder,
+                envelope,
+            ))),
+            Reply::Rename => Envelope::new(file_op_response(rename_file(
+                &mut get_write_connection().lock().unwrap(),
+                user_id,
+                target_folder,
+                envelope,
+            ))),
+        }
     }
+}
 
-    // Verify client with SQLite
-    let read_conn = db::get_read_connection().to_error("Failed to get read only connection to db");
-    let user = UserAuth::from_token(&read_conn, &token).to_error("Failed to query user auth table");
+/// Logs why waiting on a client's next request failed, which ends its session
+fn log_session_end(peer: SocketAddr, idle: Duration, err: io::Error) {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => logger::info(format!("Client {peer} disconnected")),
+        io::ErrorKind::TimedOut => logger::info(format!(
+            "Client {peer} was idle for {}s, closing the session",
+            idle.as_secs()
+        )),
+        _ => logger::warning(format!("Failed to read from {peer}: {err}")),
+    }
+}
 
-    let user_id = match user {
-        Some(user) => user.id,
-        None => {
-            let res = AuthResponse {
-                success: false,
-                failure_reason: "Invalid Token/Token Not Found".to_string(),
-            };
+/// How long the server waits on a client before giving up on it
+#[derive(Debug, Clone, Copy)]
+struct Timeouts {
+    /// Between requests, while the client has nothing for us
+    idle: Duration,
+    /// In the middle of a request, where even a busy client sends heartbeats
+    read: Duration,
+}
+
+/// How the server runs its client sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Runtime {
+    /// Each on a thread of its own from the worker pool
+    Threads,
+    /// All as tasks on an event loop, so slow and idle clients don't each hold a thread
+    Async,
+}
+
+impl FromStr for Runtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "threads" => Ok(Runtime::Threads),
+            "async" => Ok(Runtime::Async),
+            _ => Err(format!(
+                "Unknown runtime \"{s}\", expected one of: threads, async"
+            )),
+        }
+    }
+}
+
+/// How fast clients may send and recieve parts
+#[derive(Debug, Clone)]
+struct RateLimits {
+    /// Shared by every connection
+    global: Arc<RateLimiter>,
+    /// Bytes a second for each connection on its own
+    per_connection: Option<u64>,
+}
+
+fn handle_client(
+    tcp: TcpStream,
+    target_folder: &Path,
+    capabilities: &Capabilities,
+    timeouts: Timeouts,
+    limits: RateLimits,
+    tls: Option<Arc<ServerConfig>>,
+) {
+    let peer = tcp.peer_addr().to_error("Can't get the peer address??");
+    let connection_limiter = RateLimiter::new(limits.per_connection);
+    let limiters = [&*limits.global, &connection_limiter];
+    logger::info(format!("New client connected: {peer}"));
+    tcp.set_read_timeout(Some(timeouts.read))
+        .to_error("Failed to set the timeout?!?");
+
+    let plain = tcp.try_clone().to_error("Failed to clone the tcp stream");
+    let mut stream = match tls {
+        Some(config) => match tls::accept(config, plain) {
+            Ok(stream) => stream,
+            Err(err) => {
+                logger::warning(format!("TLS handshake with {peer} failed: {err}"));
+                return;
+            }
+        },
+        None => StreamIterator(Box::new(plain)),
+    };
+
+    let read_conn = db::get_read_connection().to_error("Failed to get read only connection to db");
+    let authenticated = match stream.recv::<AuthRequest>() {
+        Ok(request) => authenticate(&read_conn, capabilities, request),
+        Err(err) => Err(auth_failure(format!("Auth request not understood: {err}"))),
+    };
+    let (user_id, agreed) = match authenticated {
+        Ok(authenticated) => authenticated,
+        Err(response) => {
             stream
-                .write(&res.marshal().collect::<Vec<_>>())
+                .send(response)
                 .to_error("Failed to write fail to stream");
             return;
         }
     };
+    // Shared with the steps of uploads and downloads, which run alongside heartbeats
+    let read_conn = Mutex::new(read_conn);
+    let heartbeat = agreed.heartbeat_interval();
 
-    let response = AuthResponse {
-        success: true,
-        failure_reason: String::new(),
-    };
+    if shutdown::requested() {
+        return say_goodbye(&mut stream, &tcp, peer);
+    }
     stream
-        .write(&response.marshal().collect::<Vec<_>>())
+        .send(auth_success(&agreed))
         .to_error("Failed to return success auth message");
 
-    stream
-        .set_read_timeout(Some(Duration::new(5, 0)))
-        .to_error("Failed to set the timeout?!?");
+    // Authenticated, so serve whatever the client asks for until it hangs up
+    loop {
+        // Between requests everything the client was told about is committed, so it can pick up
+        // from there once the server is back
+        if shutdown::requested() {
+            return say_goodbye(&mut stream, &tcp, peer);
+        }
+        tcp.set_read_timeout(Some(timeouts.idle))
+            .to_error("Failed to set the timeout?!?");
+        let envelope =
+            match shutdown::while_idle(&tcp, || stream.recv_envelope()).and_then(|res| res) {
+                Ok(envelope) => envelope,
+                Err(_) if shutdown::requested() => return say_goodbye(&mut stream, &tcp, peer),
+                Err(err) => return log_session_end(peer, timeouts.idle, err),
+            };
+        tcp.set_read_timeout(Some(timeouts.read))
+            .to_error("Failed to set the timeout?!?");
 
-    let (file, file_description, db_file) = match handle_file_description(
-        &mut stream,
-        &mut response_stream,
-        &read_conn,
-        user_id,
-        target_folder,
-    ) {
-        Ok(file) => file,
-        Err(err) => {
-            let res = FileDescriptionResponse::FailMessage(err.to_string());
-            stream
-                .write(&res.marshal().collect::<Vec<_>>())
-                .to_error("Failed to write to stream");
+        let handled = match Request::route(envelope) {
+            Request::Upload(envelope) => {
+                let upload = UploadSession::new(user_id, target_folder.into(), agreed.clone());
+                exchange(
+                    &mut stream,
+                    &read_conn,
+                    heartbeat,
+                    &limiters,
+                    upload,
+                    envelope,
+                )
+            }
+            Request::Download(envelope) => {
+                let download = DownloadSession::new(target_folder.into(), agreed.clone());
+                exchange(
+                    &mut stream,
+                    &read_conn,
+                    heartbeat,
+                    &limiters,
+                    download,
+                    envelope,
+                )
+            }
+            Request::Reply(reply, envelope) => {
+                let read_conn = read_conn.lock().unwrap();
+                let answer = reply.answer(&read_conn, user_id, target_folder, envelope);
+                stream.send_envelope(answer).map_err(Into::into)
+            }
+            Request::Unexpected(reply) => {
+                logger::warning(format!("{} from {peer}", reply.message));
+                stream.send(reply).to_error("Failed to write to stream");
+                continue;
+            }
+        };
+
+        if let Err(err) = handled {
+            logger::warning(format!("Ending the session with {peer}: {err}"));
             return;
         }
+    }
+}
+
+/// Tells the client the server is shutting down, then waits for it to hang up so what it already
+/// sent can't turn our close into a reset that loses the message
+fn say_goodbye(stream: &mut StreamIterator, tcp: &TcpStream, peer: SocketAddr) {
+    logger::info(format!("Telling {peer} the server is shutting down"));
+    if stream.send(shutdown::busy()).is_err() {
+        return;
+    }
+    let mut tcp = tcp;
+    let _ = tcp.shutdown(net::Shutdown::Write);
+    let _ = tcp.set_read_timeout(Some(REJECT_TIMEOUT));
+    let _ = io::copy(&mut tcp, &mut io::sink());
+}
+
+/// Tells a client there's no room for it right now and when to try again. It gets to send its
+/// [`AuthRequest`] first, so closing doesn't throw away the reply with a reset
+fn send_busy(tcp: TcpStream, tls: Option<Arc<ServerConfig>>, retry_after: u64, reason: String) {
+    let _ = tcp.set_read_timeout(Some(REJECT_TIMEOUT));
+    let _ = tcp.set_write_timeout(Some(REJECT_TIMEOUT));
+    let mut stream = match tls {
+        Some(config) => match tls::accept(config, tcp) {
+            Ok(stream) => stream,
+            Err(_) => return,
+        },
+        None => StreamIterator(Box::new(tcp)),
     };
+    if stream.recv::<AuthRequest>().is_ok() {
+        let _ = stream.send(Busy {
+            retry_after,
+            reason,
+        });
+    }
+}
 
-    match recv_files(
-        &mut stream,
-        &mut response_stream,
+/// Takes the client through the steps of an upload or download, starting from its request in
+/// `envelope`. Only the sending and recieving happens here, with parts held to the rate limits
+fn exchange(
+    stream: &mut StreamIterator,
+    read_conn: &Mutex<Connection>,
+    heartbeat: Duration,
+    limiters: &[&RateLimiter],
+    mut session: impl Exchange,
+    envelope: Envelope,
+) -> Result<(), Box<dyn Error>> {
+    let mut received = Some(envelope);
+    loop {
+        let Step { replies, next } = stream.with_heartbeats(heartbeat, || {
+            session.step(&read_conn.lock().unwrap(), received)
+        })?;
+        for reply in replies {
+            if let Some(bytes) = throttled_bytes(&reply) {
+                stream.throttle(limiters, bytes, heartbeat)?;
+            }
+            stream.send_envelope(reply)?;
+        }
+
+        received = match next {
+            Next::Recv => {
+                let envelope = stream.recv_envelope()?;
+                if let Some(bytes) = throttled_bytes(&envelope) {
+                    stream.throttle(limiters, bytes, heartbeat)?;
+                }
+                Some(envelope)
+            }
+            Next::Send => None,
+            Next::Done => return Ok(()),
+            Next::Fail(err) => Err(err)?,
+        };
+    }
+}
+
+/// Finds the finished file a [`DownloadRequest`] asks for
+fn open_download(
+    read_conn: &Connection,
+    target_folder: &Path,
+    agreed: &Capabilities,
+    envelope: Envelope,
+) -> Result<(std::fs::File, FileDescription), Box<dyn Error>> {
+    let DownloadRequest { name, packet_size } = envelope.open()?;
+    if !agreed.packet_size_ok(packet_size) {
+        Err(format!(
+            "Invalid Packet Size: Packet Size ({packet_size}) must be between {} and {}",
+            agreed.min_packet_size, agreed.max_packet_size
+        ))?
+    }
+
+    let (name, file_path) = sanitize::resolve(target_folder, &name)?;
+    let db_file = match DbFile::find_filename(read_conn, &name)? {
+        Some(db_file) if db_file.verified() == Some(true) => db_file,
+        Some(_) => Err(format!("\"{name}\" hasn't finished uploading"))?,
+        None => Err(format!("\"{name}\" isn't on the server"))?,
+    };
+
+    let file = std::fs::File::open(file_path)?;
+    let size = file.metadata()?.len();
+    let hash = match db_file.hash.is_empty() {
+        // Uploaded before digests were kept, so there's only what's on disk to go by
+        true => {
+            let mut hasher = Sha256::new();
+            update_hash(&mut hasher, &file)?;
+            hasher.finalize().to_vec()
+        }
+        false => from_hex(&db_file.hash).ok_or("The stored hash isn't valid hex")?,
+    };
+    Ok((
         file,
-        file_description,
-        db_file,
-    ) {
-        Ok(a) => a,
-        Err(e) => {
-            logger::warning(format!("Failed in recv_files: {}", e.to_string()));
-            let res = FilePartResponse {
-                success: false,
-                message: e.to_string(),
-            };
-            stream
-                .write(&res.marshal().collect::<Vec<_>>())
-                .to_error("Failed to write to stream");
-            return;
+        FileDescription {
+            name,
+            size,
+            packet_size,
+            hash,
+        },
+    ))
+}
+
+/// Lists the files on the server that match the filters in `envelope`
+fn list_files(
+    read_conn: &Connection,
+    envelope: Envelope,
+) -> Result<Vec<ListEntry>, Box<dyn Error>> {
+    let ListRequest {
+        prefix,
+        state,
+        uploader,
+    } = envelope.open()?;
+
+    let users = UserAuth::select(read_conn, "", params![])?
+        .into_iter()
+        .map(|user| (user.id, user))
+        .collect::<HashMap<_, _>>();
+
+    let mut entries = Vec::new();
+    for file in DbFile::with_prefix(read_conn, &prefix)? {
+        let upload_state = match file.verified() {
+            Some(true) => UploadState::Complete,
+            Some(false) => UploadState::Corrupt,
+            None => UploadState::Uploading,
+        };
+        let wanted = match state {
+            ListFilter::All => true,
+            ListFilter::Complete => upload_state == UploadState::Complete,
+            ListFilter::Incomplete => upload_state != UploadState::Complete,
+        };
+
+        let user = users.get(&file.inserted_by_id);
+        let uploaded_by = |name: &str| {
+            user.is_some_and(|user| {
+                user.id.to_string() == name || user.notes.as_deref() == Some(name)
+            })
+        };
+        if !wanted || !(uploader.is_empty() || uploaded_by(&uploader)) {
+            continue;
         }
+
+        entries.push(ListEntry {
+            received_bytes: file.received_bytes(),
+            state: upload_state,
+            created_date: file.created_date.timestamp(),
+            uploader: match user.and_then(|user| user.notes.clone()) {
+                Some(notes) => notes,
+                None => format!("#{}", file.inserted_by_id),
+            },
+            name: file.filename,
+            size: file.size,
+        });
     }
+    Ok(entries)
 }
 
-fn handle_file_description(
-    stream: &mut TcpStream,
-    response_stream: &mut StreamIterator,
+/// Refuses to let anyone but whoever uploaded `file` change it
+fn check_owner(file: &DbFile, user_id: Id) -> Result<(), Box<dyn Error>> {
+    if file.inserted_by_id != user_id {
+        Err(format!(
+            "\"{}\" was uploaded by someone else",
+            file.filename
+        ))?
+    }
+    Ok(())
+}
+
+/// The row for `name`, as long as `user_id` is the one who uploaded it
+fn owned_file(con: &Connection, user_id: Id, name: &str) -> Result<DbFile, Box<dyn Error>> {
+    match DbFile::find_filename(con, name)? {
+        Some(file) => {
+            check_owner(&file, user_id)?;
+            Ok(file)
+        }
+        None => Err(format!("\"{name}\" isn't on the server"))?,
+    }
+}
+
+/// Both places a file can be, while a new version is staged beside the finished one
+fn file_paths(file_path: &Path) -> [PathBuf; 2] {
+    [file_path.to_path_buf(), sanitize::staging_path(file_path)]
+}
+
+/// Renames each `(from, to)` that's there, and only those. If one fails, the ones before it are
+/// put back. Returns the ones that were moved
+fn move_files(moves: Vec<(PathBuf, PathBuf)>) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut moved = Vec::new();
+    for (from, to) in moves {
+        match std::fs::rename(&from, &to) {
+            Ok(()) => moved.push((from, to)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => {
+                move_back(moved)?;
+                return Err(err);
+            }
+        }
+    }
+    Ok(moved)
+}
+
+/// Undoes [`move_files`]
+fn move_back(moved: Vec<(PathBuf, PathBuf)>) -> io::Result<()> {
+    for (from, to) in moved.into_iter().rev() {
+        std::fs::rename(to, from)?;
+    }
+    Ok(())
+}
+
+/// Removes the file and its row. The file is moved aside until the row is gone so a failure at
+/// either step leaves both where they were
+fn delete_file(
+    conn: &mut Connection,
+    user_id: Id,
+    target_folder: &Path,
+    envelope: Envelope,
+) -> Result<(), Box<dyn Error>> {
+    let DeleteRequest { name } = envelope.open()?;
+    let (name, file_path) = sanitize::resolve(target_folder, &name)?;
+
+    let tx = conn.transaction()?;
+    let file = owned_file(&tx, user_id, &name)?;
+    file.delete(&tx)?;
+
+    // A finished file and the new version staged beside it both go
+    let aside = |path: PathBuf| {
+        let mut aside = path.clone().into_os_string();
+        aside.push(".stable-ftp-deleting");
+        (path, PathBuf::from(aside))
+    };
+    let moved = move_files(file_paths(&file_path).into_iter().map(aside).collect())?;
+    if let Err(err) = tx.commit() {
+        move_back(moved)?;
+        return Err(err.into());
+    }
+
+    for (_, aside) in moved {
+        std::fs::remove_file(&aside)
+            .with_warning(format!("Failed to remove the deleted \"{name}\""))
+            .ok();
+    }
+    logger::info(format!("Deleted \"{name}\""));
+    Ok(())
+}
+
+/// Moves the file and its row to a new name together, never over an existing file
+fn rename_file(
+    conn: &mut Connection,
+    user_id: Id,
+    target_folder: &Path,
+    envelope: Envelope,
+) -> Result<(), Box<dyn Error>> {
+    let RenameRequest { from, to } = envelope.open()?;
+    let (from, from_path) = sanitize::resolve(target_folder, &from)?;
+    let (to, to_path) = sanitize::resolve(target_folder, &to)?;
+
+    let tx = conn.transaction()?;
+    let file = owned_file(&tx, user_id, &from)?;
+    if DbFile::find_filename(&tx, &to)?.is_some()
+        || to_path.exists()
+        || sanitize::staging_path(&to_path).exists()
+    {
+        Err(format!("\"{to}\" already exists"))?
+    }
+    file.rename(&tx, to.clone())?;
+
+    // Whatever is staged moves along with the finished file, under its staging name
+    create_parent_dirs(&to_path)?;
+    let moves = file_paths(&from_path).into_iter().zip(file_paths(&to_path));
+    let moved = move_files(moves.collect())?;
+    if let Err(err) = tx.commit() {
+        move_back(moved)?;
+        Err(err)?
+    }
+
+    logger::info(format!("Renamed \"{from}\" to \"{to}\""));
+    Ok(())
+}
+
+fn file_op_response(result: Result<(), Box<dyn Error>>) -> FileOpResponse {
+    match result {
+        Ok(()) => FileOpResponse::Done,
+        Err(err) => FileOpResponse::FailMessage(err.to_string()),
+    }
+}
+
+fn list_response(read_conn: &Connection, envelope: Envelope) -> ListResponse {
+    match list_files(read_conn, envelope) {
+        Ok(entries) => ListResponse::Entries(entries),
+        Err(err) => ListResponse::FailMessage(err.to_string()),
+    }
+}
+
+/// The status of `db_file` given the byte ranges still `missing` from it, with a hash of
+/// everything else for the client to check against its copy
+fn upload_status(
+    file: &mut std::fs::File,
+    db_file: &DbFile,
+    missing: Vec<ByteRange>,
+    packet_size: u64,
+) -> io::Result<FileStatus> {
+    // A corrupt file has to be sent again whole, however much of it arrived
+    let (missing, received_bytes) = match db_file.verified() {
+        Some(false) => (ByteRange::complement(&[], db_file.size), 0),
+        _ => (missing, db_file.received_bytes()),
+    };
+    let status = match (missing.is_empty(), received_bytes) {
+        (true, _) if db_file.verified() != Some(false) => FileStatusEnum::Exists,
+        (_, 0) => FileStatusEnum::Nonexistent,
+        _ => FileStatusEnum::Resumeable,
+    };
+
+    let received = ByteRange::complement(&missing, db_file.size);
+    let mut hasher = Sha256::new();
+    hash_ranges(&mut hasher, file, &received)?;
+
+    Ok(FileStatus {
+        id: db_file.id,
+        status,
+        received_bytes,
+        packet_size,
+        received_hash: hasher.finalize().to_vec(),
+        missing,
+    })
+}
+
+/// Files can be sent under a relative path, so make the directories they land in
+fn create_parent_dirs(file_path: &Path) -> io::Result<()> {
+    match file_path.parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+/// Refuses a file of `size` bytes that would take `user_id` past their [`UserQuota`]. The row in
+/// `replacing` doesn't count towards what they have. Hold the write lock until the file's row is
+/// written, so two uploads can't both squeeze into the same space
+fn check_quota(
+    con: &Connection,
+    user_id: Id,
+    name: &str,
+    size: u64,
+    replacing: Option<Id>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(quota) = UserQuota::for_user(con, user_id)? else {
+        return Ok(());
+    };
+    if let Some(max) = quota.max_file_size.filter(|&max| size > max) {
+        Err(format!(
+            "Quota exceeded: \"{name}\" is {} but files may be at most {}",
+            file_size_text(size),
+            file_size_text(max)
+        ))?
+    }
+
+    let (files, bytes) = DbFile::usage(con, user_id, replacing)?;
+    if let Some(max) = quota.max_files.filter(|&max| files >= max) {
+        Err(format!(
+            "Quota exceeded: you already have {files} files on the server, the most allowed is {max}"
+        ))?
+    }
+    // The size comes from the client, so it can be anything
+    let total = bytes.checked_add(size);
+    if let Some(max) = quota
+        .max_bytes
+        .filter(|&max| total.is_none_or(|total| total > max))
+    {
+        Err(format!(
+            "Quota exceeded: \"{name}\" ({}) would bring your files to {}, over the limit of {}",
+            file_size_text(size),
+            total.map_or_else(|| "more than can be counted".to_string(), file_size_text),
+            file_size_text(max)
+        ))?
+    }
+    Ok(())
+}
+
+/// Finds the file and row for an upload, or makes them if it's new. Whatever was already
+/// recieved of it is kept, as long as it wasn't found to be corrupt. Only whoever uploaded a file
+/// may send a different version of it
+fn open_upload(
     read_conn: &Connection,
+    write_conn: &Mutex<Connection>,
     user_id: Id,
     target_folder: &Path,
-) -> Result<(std::fs::File, FileStatus, DbFile), Box<dyn Error>> {
+    agreed: &Capabilities,
+    file_description: &FileDescription,
+) -> Result<(std::fs::File, DbFile), Box<dyn Error>> {
     let FileDescription {
         name,
         size,
         packet_size,
-    } = FileDescription::unmarshal(response_stream)?;
+        hash,
+    } = file_description;
+    let (size, packet_size) = (*size, *packet_size);
+    let hash = to_hex(hash);
 
-    if packet_size < MIN_PACKET_SIZE {
+    if !agreed.packet_size_ok(packet_size) {
         Err(format!(
-            "Invalid Packet Size: Packet Size ({}) must be >= {MIN_PACKET_SIZE}",
-            packet_size
+            "Invalid Packet Size: Packet Size ({packet_size}) must be between {} and {}",
+            agreed.min_packet_size, agreed.max_packet_size
         ))?
     }
 
+    let (name, file_path) = sanitize::resolve(target_folder, name)?;
+    let staging = sanitize::staging_path(&file_path);
     let file = DbFile::find_filename(read_conn, &name)?;
 
-    let (mut file, response, dbfile) = match file {
-        Some(file) => {
-            let status = match file.current_packet() == file.total_packets {
-                true => FileStatusEnum::Exists,
-                false => FileStatusEnum::Resumeable,
-            };
+    let (mut file, db_file) = match file {
+        Some(mut file) => {
+            // The client's copy changed since the last attempt. What we already have is checked
+            // against it through the recieved hash, so the row can follow the new version
+            let changed = hash != file.hash;
+            if changed {
+                check_owner(&file, user_id)?;
+            }
+
+            if file.verified() == Some(false) {
+                logger::warning(format!(
+                    "\"{}\" failed verification last time, starting it over",
+                    file.filename
+                ));
+                file = file.reset_progress(&write_conn.lock().unwrap())?;
+            }
 
-            let file_path = target_folder.join(&file.filename);
+            if changed {
+                let conn = write_conn.lock().unwrap();
+                check_quota(&conn, user_id, &name, size, Some(file.id))?;
+                // A finished file stays under its own name until the new version verifies, which
+                // is staged from scratch since staging holds none of it
+                let replacing = file.verified() == Some(true);
+                if replacing {
+                    file = file.reset_progress(&conn)?;
+                }
+                file = file.update_source(&conn, hash, size)?;
+                // The old parts cover all of the new version, so they can't be what it holds
+                if file.received_bytes() == size {
+                    file = file.reset_progress(&conn)?;
+                }
+                drop(conn);
+                if replacing {
+                    create_parent_dirs(&staging)?;
+                    std::fs::File::create(&staging)?;
+                }
+            }
+
+            let file_path = stored_path(&file, file_path);
 
             // Ensure the file is *actually* there
-            let real_file = match std::path::Path::new(&file_path).exists() {
+            let real_file = match file_path.exists() {
                 true => std::fs::File::options()
                     .read(true)
                     .write(true)
@@ -174,121 +787,270 @@ fn handle_file_description(
                         "The file \"{}\" from db doesn't actually exist, creating it now",
                         file.filename
                     ));
+                    create_parent_dirs(&file_path)?;
                     std::fs::File::create_new(file_path)
                 }
             }?;
-
-            // Find file entry in db
-            let file_status = FileStatus {
-                status: status.into(),
-                id: file.id,
-                request_packet: file.current_packet(),
-                packet_size: file.packet_size,
-                total_packets: file.total_packets,
-            };
+            if changed {
+                real_file.set_len(size)?;
+            }
 
             logger::info(format!(
-                "Resuming file download for \"{}\" on {}/{}",
+                "Resuming file download for \"{}\" at {}/{}",
                 file.filename,
-                file.current_packet(),
-                file.total_packets
+                file_size_text(file.received_bytes()),
+                file_size_text(file.size)
             ));
-            (
-                real_file,
-                FileDescriptionResponse::Status(file_status),
-                file,
-            )
+            (real_file, file)
         }
         None => {
-            let total_packets = num_packets(packet_size, size);
-
+            let conn = write_conn.lock().unwrap();
+            check_quota(&conn, user_id, &name, size, None)?;
             let db_file = DbFile::new()
                 .with_filename(&name)
-                .with_total_packets(total_packets)
-                .with_packet_size(packet_size)
+                .with_size(size)
+                .with_hash(hash)
                 .with_inserted_by_id(user_id)
-                .build_val(&get_write_connection().lock().unwrap())?;
+                .build_val(&conn)?;
+            drop(conn);
 
-            let mut file = std::fs::File::create_new(target_folder.join(&name))?;
-            file.seek(io::SeekFrom::Start(size - 1))?;
-            file.write(&[69])?;
+            create_parent_dirs(&staging)?;
+            let file = std::fs::File::create_new(staging)?;
+            file.set_len(size)?;
             logger::info(format!(
                 "Adding new file \"{name}\" with size {}",
                 file_size_text(size)
             ));
 
-            let file_res = FileDescriptionResponse::Status(FileStatus {
-                status: FileStatusEnum::Nonexistent.into(),
-                id: db_file.id,
-                request_packet: 0,
-                packet_size,
-                total_packets,
-            });
-
-            (file, file_res, db_file)
+            (file, db_file)
         }
     };
 
-    let seek_pos = dbfile.current_packet() * dbfile.packet_size;
-    file.seek(io::SeekFrom::Start(seek_pos))
-        .with_warning("Failed to seek to the right part of the file")?;
-
-    stream.write(&response.clone().marshal().collect::<Vec<_>>())?;
-    let file_status = match response {
-        FileDescriptionResponse::Status(file_status) => file_status,
-        FileDescriptionResponse::FailMessage(_) => {
-            logger::error("There shouldn't be a failure response at this point")
-        }
+    // Everything's there but it was never checked, the server may have stopped right before
+    let unchecked = db_file.verified().is_none() && db_file.received_bytes() == db_file.size;
+    let mut db_file = match unchecked {
+        true => finish_upload(&mut file, write_conn, db_file, target_folder)?,
+        false => db_file,
     };
-    Ok((file, file_status, dbfile))
+    // Corrupt, so the client has to send it all again
+    if db_file.verified() == Some(false) {
+        db_file = db_file.reset_progress(&write_conn.lock().unwrap())?;
+    }
+    Ok((file, db_file))
 }
 
-fn recv_files(
-    stream: &mut TcpStream,
-    response_stream: &mut StreamIterator,
-    mut file: std::fs::File,
-    file_status: FileStatus,
-    mut db_file: DbFile,
-) -> Result<(), Box<dyn Error>> {
-    for current_packet in file_status.request_packet..file_status.total_packets {
-        let part_num = u64::unmarshal(response_stream)?;
-        let len = usize::unmarshal(response_stream)?;
-        if len as u64 > file_status.packet_size {
+/// Throws away everything recieved of `db_file` because the client's copy of it changed. Only
+/// whoever uploaded it may do that
+fn restart_upload(
+    file: &std::fs::File,
+    write_conn: &Mutex<Connection>,
+    db_file: DbFile,
+    user_id: Id,
+    hash: String,
+    size: u64,
+) -> Result<DbFile, Box<dyn Error>> {
+    check_owner(&db_file, user_id)?;
+    logger::info(format!(
+        "Client's copy of \"{}\" changed since the last attempt, starting it over",
+        db_file.filename
+    ));
+    let conn = write_conn.lock().unwrap();
+    check_quota(&conn, user_id, &db_file.filename, size, Some(db_file.id))?;
+    let db_file = db_file
+        .reset_progress(&conn)?
+        .update_source(&conn, hash, size)?;
+    drop(conn);
+
+    file.set_len(0)?;
+    file.set_len(size)?;
+    Ok(db_file)
+}
+
+/// Hashes the finished file to check it matches what the client said it would be
+fn verify_upload(file: &mut std::fs::File, db_file: &DbFile) -> io::Result<bool> {
+    logger::info(format!(
+        "Successfully recieved all the data for \"{}\"",
+        db_file.filename
+    ));
+
+    let mut hasher = Sha256::new();
+    file.seek(io::SeekFrom::Start(0))?;
+    update_hash(&mut hasher, &mut *file)?;
+    let digest = to_hex(&hasher.finalize());
+
+    let verified = digest == db_file.hash;
+    match verified {
+        true => logger::info(format!("Verified the hash of \"{}\"", db_file.filename)),
+        false => logger::warning(format!(
+            "Hash mismatch for \"{}\": expected {} but recieved {digest}",
+            db_file.filename, db_file.hash
+        )),
+    }
+    Ok(verified)
+}
+
+/// Verifies the upload that just came in complete and, if it's what the client sent, moves it
+/// from staging to its own name. A corrupt one stays staged until the client starts it over
+fn finish_upload(
+    file: &mut std::fs::File,
+    write_conn: &Mutex<Connection>,
+    db_file: DbFile,
+    target_folder: &Path,
+) -> Result<DbFile, Box<dyn Error>> {
+    let verified = verify_upload(file, &db_file)?;
+
+    // Held until the row says where the file is, so a delete or rename can't slip in between
+    let conn = write_conn.lock().unwrap();
+    // Renames don't wait for uploads to finish, so go by the name it has now
+    let db_file = db_file.reload(&conn)?;
+    if verified {
+        let (_, file_path) = sanitize::resolve(target_folder, &db_file.filename)?;
+        std::fs::rename(sanitize::staging_path(&file_path), &file_path)?;
+    }
+    Ok(db_file
+        .set_verified(&conn, verified)
+        .with_warning("Failed to record the verification result")?)
+}
+
+/// Where the file for `db_file` is right now, given the path for its name. Only verified files
+/// are out of staging
+fn stored_path(db_file: &DbFile, file_path: PathBuf) -> PathBuf {
+    match db_file.verified() {
+        Some(true) => file_path,
+        _ => sanitize::staging_path(&file_path),
+    }
+}
+
+/// What became of a part the client sent
+enum PartOutcome {
+    /// Written to the file, this many bytes of it
+    Stored(u64),
+    /// It failed its checksum, so the client has to send it again
+    Resend,
+}
+
+/// A file that parts are coming in for
+struct Upload {
+    file: std::fs::File,
+    db_file: DbFile,
+    /// Where the file goes once it's complete
+    target_folder: PathBuf,
+    packet_size: u64,
+    compression: Compression,
+    checksum: Checksum,
+    /// Parts in a row that failed their checksum
+    retries: u32,
+}
+
+impl Upload {
+    fn new(
+        file: std::fs::File,
+        db_file: DbFile,
+        packet_size: u64,
+        target_folder: &Path,
+        agreed: &Capabilities,
+    ) -> Self {
+        Self {
+            file,
+            db_file,
+            target_folder: target_folder.to_path_buf(),
+            packet_size,
+            compression: agreed.compression(),
+            checksum: agreed.checksum(),
+            retries: 0,
+        }
+    }
+
+    /// Checks `part` against what was agreed on, then writes it out and records it. Whichever
+    /// connection brings in the last part checks the whole file, so this can take a while
+    fn store(&mut self, part: FilePart) -> Result<PartOutcome, Box<dyn Error>> {
+        let FilePart {
+            offset,
+            checksum,
+            compression: part_compression,
+            data,
+        } = part;
+        // Compressed parts are only sent when they come out smaller, so this holds either way
+        if data.len() as u64 > self.packet_size {
             Err(format!(
-                "Packet too large ({len} > {})",
-                file_status.packet_size
+                "Packet too large ({} > {})",
+                data.len(),
+                self.packet_size
             ))?;
         }
-        let mut data = Vec::with_capacity(len);
-        unsafe {
-            data.set_len(len);
+
+        if self.checksum.compute(&data) != checksum {
+            self.retries += 1;
+            if self.retries > MAX_PART_RETRIES {
+                Err(format!(
+                    "Part at {offset} failed its checksum {} times in a row",
+                    self.retries
+                ))?;
+            }
+            logger::warning(format!(
+                "Checksum mismatch on the part at {offset} of \"{}\", asking for it again",
+                self.db_file.filename
+            ));
+            return Ok(PartOutcome::Resend);
         }
-        stream.read_exact(&mut data)?;
+        self.retries = 0;
 
-        assert!(
-            part_num == current_packet,
-            "Part Num: {part_num} =! Expected Num: {current_packet}"
-        );
-        file.write_all(&data)
+        if part_compression != Compression::None && part_compression != self.compression {
+            Err(format!(
+                "Part at {offset} is compressed with {part_compression} but {} was agreed on",
+                self.compression
+            ))?;
+        }
+        let data = part_compression.decompress(&data, self.packet_size)?;
+
+        let len = data.len() as u64;
+        if len == 0
+            || offset
+                .checked_add(len)
+                .is_none_or(|end| end > self.db_file.size)
+        {
+            Err(format!(
+                "Part at {offset} with {len} bytes doesn't fit in the file ({} bytes)",
+                self.db_file.size
+            ))?;
+        }
+
+        self.file.seek(io::SeekFrom::Start(offset))?;
+        self.file
+            .write_all(&data)
             .with_warning("Failed to write data to file")?;
-        db_file = db_file
-            .inc_current_packet(&get_write_connection().lock().unwrap())
-            .with_warning("Failed to increment current packet in db")?;
+        let newly_received;
+        (self.db_file, newly_received) = self
+            .db_file
+            .clone()
+            .mark_received(&get_write_connection().lock().unwrap(), offset, len)
+            .with_warning("Failed to mark the part as recieved in db")?;
 
-        let res = FilePartResponse {
-            success: true,
-            message: String::new(),
-        };
-        stream
-            .write(&res.marshal().collect::<Vec<_>>())
-            .with_warning("Failed to write FilePartResponse to stream")?;
+        if newly_received && self.db_file.received_bytes() == self.db_file.size {
+            self.db_file = finish_upload(
+                &mut self.file,
+                get_write_connection(),
+                self.db_file.clone(),
+                &self.target_folder,
+            )?;
+        }
+        Ok(PartOutcome::Stored(len))
     }
 
-    logger::info(format!(
-        "Successfully recieved all the data for \"{}\"",
-        db_file.filename
-    ));
-    Ok(())
+    /// How the upload stands once this connection sent everything it was going to
+    fn result(&self) -> Result<UploadResult, Box<dyn Error>> {
+        let db_file = self
+            .db_file
+            .reload(&get_write_connection().lock().unwrap())?;
+        Ok(match db_file.verified() {
+            Some(true) => UploadResult::Verified,
+            Some(false) => UploadResult::Corrupt(format!(
+                "Hash of \"{}\" didn't match {}",
+                db_file.filename, db_file.hash
+            )),
+            None => UploadResult::Pending(db_file.size - db_file.received_bytes()),
+        })
+    }
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -307,41 +1069,280 @@ struct Args {
     #[arg(short, long)]
     #[arg(default_value = "stable-ftp-ingress")]
     target_folder: PathBuf,
+
+    /// PEM certificate chain to serve TLS with. Without it connections are unencrypted
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `--tls-cert`
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Compression codecs clients are allowed to use, comma separated (zstd, deflate, none)
+    #[arg(long, value_delimiter = ',')]
+    #[arg(default_value = "zstd,deflate")]
+    compression: Vec<Compression>,
+
+    /// Checksums clients are allowed to check parts with, comma separated (xxh3, crc32c)
+    #[arg(long, value_delimiter = ',')]
+    #[arg(default_value = "xxh3,crc32c")]
+    checksum: Vec<Checksum>,
+
+    /// Smallest part size clients may upload or download with, in bytes. Every part costs a
+    /// database write, so this keeps clients that adapt their part size from going too small
+    #[arg(long)]
+    #[arg(default_value_t = MIN_PACKET_SIZE)]
+    min_packet_size: u64,
+
+    /// Largest part size clients may upload or download with, in bytes. Every connection can hold
+    /// a few buffers this big, so raising it multiplies with `--max-connections`
+    #[arg(long)]
+    #[arg(default_value_t = DEFAULT_PACKET_SIZE)]
+    max_packet_size: u64,
+
+    /// Most data a second all clients together may send or recieve, like `100MB/s`
+    #[arg(long, value_parser = parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Most data a second a single connection may send or recieve, like `20MB/s`
+    #[arg(long, value_parser = parse_rate)]
+    limit_rate_per_connection: Option<u64>,
+
+    /// How client sessions are run: `threads` gives each its own worker thread, `async` runs them
+    /// all on an event loop, which copes better with many slow clients
+    #[arg(long)]
+    #[arg(default_value = "threads")]
+    runtime: Runtime,
+
+    /// Threads running client sessions, the most sessions that run at once. With `--runtime async`
+    /// it's the most threads doing database and file work for the sessions instead
+    #[arg(long)]
+    #[arg(default_value_t = 64)]
+    workers: usize,
+
+    /// Connections that may wait for a free worker before new ones are told the server is busy.
+    /// Only for `--runtime threads`
+    #[arg(long)]
+    #[arg(default_value_t = 64)]
+    accept_queue: usize,
+
+    /// Most connections open at once, running or waiting
+    #[arg(long)]
+    #[arg(default_value_t = 128)]
+    max_connections: usize,
+
+    /// Most connections open at once from a single IP address
+    #[arg(long)]
+    #[arg(default_value_t = 16)]
+    max_connections_per_ip: usize,
+
+    /// Seconds clients that are turned away are told to wait before trying again
+    #[arg(long)]
+    #[arg(default_value_t = 10)]
+    busy_retry_after: u64,
+
+    /// Seconds a client may sit between requests before its session is closed
+    #[arg(long)]
+    #[arg(default_value_t = 300)]
+    idle_timeout: u64,
+
+    /// Seconds a client may go without sending anything in the middle of a request. Clients that
+    /// are busy send heartbeats, so running into this means the client is gone
+    #[arg(long)]
+    #[arg(default_value_t = 30)]
+    read_timeout: u64,
+
+    /// Seconds sessions get to finish the part in hand after SIGINT or SIGTERM before the server
+    /// exits anyway
+    #[arg(long)]
+    #[arg(default_value_t = 30)]
+    shutdown_timeout: u64,
 }
 
 fn init_db() -> Result<(), rusqlite::Error> {
     let conn = get_write_connection().lock().unwrap();
     let _ = conn.execute("PRAGMA foreign_keys = ON;", []);
     let _ = conn.execute("PRAGMA journal_mode = WAL;", []);
+    db::init(&conn)
+}
 
-    UserAuth::create_table(&conn)?;
-    DbFile::create_table(&conn)?;
+/// Moves the uploads the database marked as written under their own name, from before uploads
+/// were staged, to where they're staged now
+fn stage_old_uploads(conn: &Connection, target_folder: &Path) -> Result<(), Box<dyn Error>> {
+    for file in UnstagedUpload::files(conn)? {
+        match sanitize::resolve(target_folder, &file.filename) {
+            Ok((_, file_path)) => {
+                let staging = sanitize::staging_path(&file_path);
+                if file.verified().is_none() && file_path.exists() && !staging.exists() {
+                    std::fs::rename(&file_path, &staging)?;
+                }
+            }
+            Err(err) => logger::warning(format!("Leaving the old upload where it is: {err}")),
+        }
+        UnstagedUpload::done(conn, &file)?;
+    }
     Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let Args { ip, target_folder } = Args::parse();
+    let Args {
+        ip,
+        target_folder,
+        tls_cert,
+        tls_key,
+        compression,
+        checksum,
+        min_packet_size,
+        max_packet_size,
+        limit_rate,
+        limit_rate_per_connection,
+        runtime,
+        workers,
+        accept_queue,
+        max_connections,
+        max_connections_per_ip,
+        busy_retry_after,
+        idle_timeout,
+        read_timeout,
+        shutdown_timeout,
+    } = Args::parse();
+
+    if !(MIN_PACKET_SIZE..=MAX_PACKET_SIZE).contains(&max_packet_size) {
+        logger::error(format!(
+            "max packet size ({max_packet_size}) must be between {MIN_PACKET_SIZE} and {MAX_PACKET_SIZE}"
+        ))
+    }
+    if !(MIN_PACKET_SIZE..=max_packet_size).contains(&min_packet_size) {
+        logger::error(format!(
+            "min packet size ({min_packet_size}) must be between {MIN_PACKET_SIZE} and the max packet size ({max_packet_size})"
+        ))
+    }
+    if workers == 0 || max_connections == 0 || max_connections_per_ip == 0 {
+        logger::error("workers and connection limits must be at least 1")
+    }
+    if idle_timeout == 0 || read_timeout == 0 {
+        logger::error("Timeouts must be at least a second")
+    }
+    let timeouts = Timeouts {
+        idle: Duration::from_secs(idle_timeout),
+        read: Duration::from_secs(read_timeout),
+    };
+    let capabilities = Capabilities {
+        compression,
+        checksum,
+        window: MAX_WINDOW,
+        min_packet_size,
+        max_packet_size,
+        heartbeat_interval: timeouts.read.as_millis() as u64 / HEARTBEATS_PER_TIMEOUT,
+        protocol: vec![PROTOCOL_REVISION],
+    };
+
+    if let Some(rate) = limit_rate {
+        logger::info(format!(
+            "Limiting all clients to {} in total",
+            rate_text(rate)
+        ));
+    }
+    if let Some(rate) = limit_rate_per_connection {
+        logger::info(format!("Limiting each connection to {}", rate_text(rate)));
+    }
+    let limits = RateLimits {
+        global: Arc::new(RateLimiter::new(limit_rate)),
+        per_connection: limit_rate_per_connection,
+    };
+
+    let tls = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => {
+            let config = tls::server_config(&cert, &key).to_error("Failed to load TLS certificate");
+            for cert in tls::load_certs(&cert).to_error("Failed to load TLS certificate") {
+                logger::info(format!(
+                    "Serving TLS with certificate {}",
+                    tls::fingerprint(&cert)
+                ));
+            }
+            Some(config)
+        }
+        _ => None,
+    };
 
     std::fs::create_dir_all(&target_folder).to_error("Failed to create folder");
     init_db().to_error("Failed to create db");
+    stage_old_uploads(&get_write_connection().lock().unwrap(), &target_folder)
+        .to_error("Failed to move old uploads to staging");
+    shutdown::install(busy_retry_after).to_error("Failed to set up signal handling");
+    let shutdown_timeout = Duration::from_secs(shutdown_timeout);
+
+    if runtime == Runtime::Async {
+        logger::info(format!(
+            "Running sessions on an event loop with {workers} threads for their file work, with up to {max_connections} connections and {max_connections_per_ip} from each address"
+        ));
+        let server = async_server::Server {
+            target_folder,
+            capabilities,
+            timeouts,
+            limits,
+            tls,
+            admission: Admission::new(max_connections, max_connections_per_ip),
+            busy_retry_after,
+        };
+        let addrs = ip.to_socket_addrs()?.collect();
+        close_down(async_server::run(server, addrs, workers, shutdown_timeout)?);
+    }
+
+    let connection_limits = ConnectionLimits {
+        workers,
+        queue: accept_queue,
+        max_connections,
+        max_per_ip: max_connections_per_ip,
+    };
+    logger::info(format!(
+        "Running {workers} workers, with up to {max_connections} connections and {max_connections_per_ip} from each address"
+    ));
+    let busy_tls = tls.clone();
+    let pool = Arc::new(Pool::new(
+        connection_limits,
+        move |stream| {
+            handle_client(
+                stream,
+                &target_folder,
+                &capabilities,
+                timeouts,
+                limits.clone(),
+                tls.clone(),
+            )
+        },
+        move |stream, reason| send_busy(stream, busy_tls.clone(), busy_retry_after, reason),
+    ));
 
     let listeners = ip
         .to_socket_addrs()?
         .map(|ip| {
-            let target_folder = target_folder.clone();
+            let pool = pool.clone();
             std::thread::spawn(move || {
                 let listener = TcpListener::bind(ip).to_error("Failed to bind to IP");
                 logger::info(&format!("Server listening on {ip}"));
 
-                for conn in listener.incoming() {
-                    let fname = target_folder.clone();
-                    match conn.with_warning("Failed to connect") {
-                        Ok(stream) => {
-                            std::thread::spawn(move || handle_client(stream, &fname));
+                // Polled so a shutdown isn't stuck behind waiting on the next connection
+                listener
+                    .set_nonblocking(true)
+                    .to_error("Failed to set up the listener");
+                while !shutdown::requested() {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            if let Ok(()) = stream
+                                .set_nonblocking(false)
+                                .with_warning("Failed to set up the connection")
+                            {
+                                pool.dispatch(stream)
+                            }
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            std::thread::sleep(ACCEPT_POLL)
                         }
-                        _ => (),
+                        Err(err) => logger::warning(format!("Failed to connect: {err}")),
                     }
                 }
+                logger::info(format!("Stopped listening on {ip}"));
             })
         })
         .collect::<Vec<_>>();
@@ -352,5 +1353,450 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             Err(_) => (),
         };
     }
-    Ok(())
+    close_down(pool.wait_closed(shutdown_timeout))
+}
+
+/// The last of a shutdown, once every session ended or the time for them ran out
+fn close_down(all_closed: bool) -> ! {
+    match all_closed {
+        true => logger::info("Every session has ended"),
+        false => {
+            logger::warning("Cutting off the sessions still running past the shutdown timeout")
+        }
+    }
+    // Held until the process exits, so nothing that's still running gets to write after this
+    let _conn = match db::close_write_connection() {
+        Ok(conn) => conn,
+        Err(err) => {
+            logger::warning(format!("Failed to close the database cleanly: {err}"));
+            std::process::exit(1)
+        }
+    };
+    logger::info("Closed the database, exiting");
+    std::process::exit(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DB_FILE: &str = "stable-ftp.sqlite";
+
+    /// A fresh database and folder with users 1 and 2, and a verified file from user 1 for each of
+    /// `names`
+    fn setup(test: &str, names: &[&str]) -> (Connection, PathBuf) {
+        let folder = std::env::temp_dir().join(format!("stable-ftp-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+
+        let conn = Connection::open(folder.join(DB_FILE)).unwrap();
+        db::init(&conn).unwrap();
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (id, token) VALUES (1, 'one'), (2, 'two')",
+                UserAuth::TABLE_NAME
+            ),
+            [],
+        )
+        .unwrap();
+        for name in names {
+            DbFile::new()
+                .with_filename(*name)
+                .with_size(5)
+                .with_hash(String::new())
+                .with_inserted_by_id(1)
+                .build_val(&conn)
+                .unwrap()
+                .set_verified(&conn, true)
+                .unwrap();
+            std::fs::write(folder.join(name), "hello").unwrap();
+        }
+        (conn, folder)
+    }
+
+    /// Another connection to the database from [`setup`], for whatever takes the write lock itself
+    fn write_conn(folder: &Path) -> Mutex<Connection> {
+        Mutex::new(Connection::open(folder.join(DB_FILE)).unwrap())
+    }
+
+    /// Opens an upload of `name` from `user_id` with `data` as its content
+    fn upload(
+        conn: &Connection,
+        writer: &Mutex<Connection>,
+        user_id: Id,
+        folder: &Path,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(std::fs::File, DbFile), String> {
+        let description = FileDescription {
+            name: name.to_string(),
+            size: data.len() as u64,
+            packet_size: MIN_PACKET_SIZE,
+            hash: Sha256::digest(data).to_vec(),
+        };
+        open_upload(
+            conn,
+            writer,
+            user_id,
+            folder,
+            &Capabilities::default(),
+            &description,
+        )
+        .map_err(|err| err.to_string())
+    }
+
+    fn delete(conn: &mut Connection, user_id: Id, folder: &Path, name: &str) -> Result<(), String> {
+        let envelope = Envelope::new(DeleteRequest {
+            name: name.to_string(),
+        });
+        delete_file(conn, user_id, folder, envelope).map_err(|err| err.to_string())
+    }
+
+    fn rename(
+        conn: &mut Connection,
+        user_id: Id,
+        folder: &Path,
+        from: &str,
+        to: &str,
+    ) -> Result<(), String> {
+        let envelope = Envelope::new(RenameRequest {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+        rename_file(conn, user_id, folder, envelope).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn authenticates() {
+        let (conn, folder) = setup("auth", &[]);
+        let server = Capabilities {
+            protocol: vec![PROTOCOL_REVISION],
+            ..Capabilities::default()
+        };
+        let request = |version: &str, token: &str, capabilities: Capabilities| AuthRequest {
+            version: version.into(),
+            token: token.to_string(),
+            capabilities: capabilities.to_wire(),
+        };
+        let client = |checksum, protocol| Capabilities {
+            checksum: vec![checksum],
+            protocol: vec![protocol],
+            ..Capabilities::default()
+        };
+        let refusal = |request| match authenticate(&conn, &server, request) {
+            Ok(_) => panic!("Let the client in"),
+            Err(response) => (response.success, response.failure_reason),
+        };
+        let version = env!("CARGO_PKG_VERSION");
+        let current = client(Checksum::Crc32c, PROTOCOL_REVISION);
+
+        let (user_id, agreed) =
+            authenticate(&conn, &server, request(version, "two", current.clone())).unwrap();
+        assert_eq!((user_id, agreed.checksum()), (2, Checksum::Crc32c));
+        assert_eq!(
+            refusal(request(version, "three", current.clone())),
+            (
+                false,
+                "Failed to authenticate: Invalid Token/Token Not Found".to_string()
+            )
+        );
+
+        // Older and newer clients are fine as long as they speak the same protocol
+        for version in ["0.2.0", "0.4.1", "1.0.0"] {
+            assert!(authenticate(&conn, &server, request(version, "two", current.clone())).is_ok());
+        }
+        // One from before revisions were exchanged speaks the first
+        let unsaid = AuthRequest {
+            capabilities: Vec::new(),
+            ..request("0.2.0", "two", current.clone())
+        };
+        assert!(authenticate(&conn, &server, unsaid).is_ok());
+
+        assert!(
+            refusal(request(version, "two", client(Checksum::Crc32c, 99)))
+                .1
+                .contains("No common protocol revision")
+        );
+        assert!(
+            refusal(request(
+                version,
+                "two",
+                client(Checksum::Xxh3, PROTOCOL_REVISION)
+            ))
+            .1
+            .contains("No common checksum")
+        );
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn enforces_quotas() {
+        let (conn, folder) = setup("quota", &["a.txt", "b.txt"]);
+        let check = |size, replacing| check_quota(&conn, 1, "c.txt", size, replacing);
+
+        // No row means no limits
+        assert!(check(u64::MAX / 2, None).is_ok());
+
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (user_id, max_bytes, max_files, max_file_size) VALUES (1, NULL, 2, NULL)",
+                UserQuota::TABLE_NAME
+            ),
+            [],
+        )
+        .unwrap();
+        assert!(
+            check(5, None)
+                .unwrap_err()
+                .to_string()
+                .contains("already have 2 files")
+        );
+        let a = DbFile::find_filename(&conn, "a.txt").unwrap().unwrap();
+        assert!(check(5, Some(a.id)).is_ok());
+
+        let set = |column: &str, value: Option<u64>| {
+            conn.execute(
+                &format!("UPDATE {} SET {column} = ?1", UserQuota::TABLE_NAME),
+                params![value],
+            )
+            .unwrap();
+        };
+        set("max_files", None);
+        set("max_bytes", Some(15));
+        assert!(check(5, None).is_ok());
+        assert!(
+            check(6, None)
+                .unwrap_err()
+                .to_string()
+                .contains("over the limit")
+        );
+        // Replacing a.txt frees up its 5 bytes
+        assert!(check(10, Some(a.id)).is_ok());
+        assert!(check(11, Some(a.id)).is_err());
+        // Sizes that don't fit beside what they have are over any limit
+        for size in [u64::MAX - 2, 1 << 63] {
+            assert!(
+                check(size, None)
+                    .unwrap_err()
+                    .to_string()
+                    .contains("over the limit")
+            );
+        }
+
+        set("max_bytes", None);
+        set("max_file_size", Some(8));
+        assert!(check(8, None).is_ok());
+        assert!(
+            check(9, Some(a.id))
+                .unwrap_err()
+                .to_string()
+                .contains("at most")
+        );
+
+        // Only their own files count against them
+        set("max_file_size", None);
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (user_id, max_bytes, max_files, max_file_size) VALUES (2, 5, 1, 5)",
+                UserQuota::TABLE_NAME
+            ),
+            [],
+        )
+        .unwrap();
+        assert!(check_quota(&conn, 2, "c.txt", 5, None).is_ok());
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn never_calls_a_corrupt_file_done() {
+        let (conn, folder) = setup("status", &["a.txt"]);
+        let mut file = std::fs::File::open(folder.join("a.txt")).unwrap();
+        let a = DbFile::find_filename(&conn, "a.txt").unwrap().unwrap();
+        let status = upload_status(&mut file, &a, Vec::new(), 1024).unwrap();
+        assert!(matches!(status.get_status(), FileStatusEnum::Exists));
+
+        // Every byte came in, but they didn't hash to what the client said
+        let a = a.set_verified(&conn, false).unwrap();
+        let status = upload_status(&mut file, &a, Vec::new(), 1024).unwrap();
+        assert!(matches!(status.get_status(), FileStatusEnum::Nonexistent));
+        assert_eq!(status.received_bytes, 0);
+        assert_eq!(status.missing, vec![ByteRange { start: 0, end: 5 }]);
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn deletes_only_owned_files() {
+        let (mut conn, folder) = setup("delete", &["a.txt"]);
+
+        assert_eq!(
+            delete(&mut conn, 2, &folder, "a.txt").unwrap_err(),
+            "\"a.txt\" was uploaded by someone else"
+        );
+        assert!(folder.join("a.txt").exists());
+        assert!(DbFile::find_filename(&conn, "a.txt").unwrap().is_some());
+
+        delete(&mut conn, 1, &folder, "a.txt").unwrap();
+        assert!(!folder.join("a.txt").exists());
+        assert!(DbFile::find_filename(&conn, "a.txt").unwrap().is_none());
+        assert_eq!(
+            delete(&mut conn, 1, &folder, "a.txt").unwrap_err(),
+            "\"a.txt\" isn't on the server"
+        );
+        assert!(delete(&mut conn, 1, &folder, "../a.txt").is_err());
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn reuploads_only_owned_files() {
+        let (conn, folder) = setup("reupload", &["a.txt"]);
+        let writer = write_conn(&folder);
+        let a = DbFile::find_filename(&conn, "a.txt").unwrap().unwrap();
+
+        assert_eq!(
+            upload(&conn, &writer, 2, &folder, "a.txt", b"world").unwrap_err(),
+            "\"a.txt\" was uploaded by someone else"
+        );
+        assert!(
+            restart_upload(
+                &std::fs::File::open(folder.join("a.txt")).unwrap(),
+                &writer,
+                a.clone(),
+                2,
+                String::new(),
+                5
+            )
+            .is_err()
+        );
+        let a = a.reload(&conn).unwrap();
+        assert_eq!((a.verified(), a.hash.as_str()), (Some(true), ""));
+        assert!(!sanitize::staging_path(&folder.join("a.txt")).exists());
+
+        // Whoever uploaded it can send a new version, which is staged beside the old one
+        let (_, a) = upload(&conn, &writer, 1, &folder, "a.txt", b"world").unwrap();
+        assert_eq!((a.verified(), a.received_bytes()), (None, 0));
+        assert_eq!(
+            std::fs::read_to_string(folder.join("a.txt")).unwrap(),
+            "hello"
+        );
+        assert!(sanitize::staging_path(&folder.join("a.txt")).exists());
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn moves_both_versions_during_a_replacement() {
+        let (mut conn, folder) = setup("replace", &["a.txt"]);
+        let writer = write_conn(&folder);
+        upload(&conn, &writer, 1, &folder, "a.txt", b"world").unwrap();
+        std::fs::write(sanitize::staging_path(&folder.join("a.txt")), "wor").unwrap();
+
+        rename(&mut conn, 1, &folder, "a.txt", "dir/c.txt").unwrap();
+        assert!(!folder.join("a.txt").exists());
+        assert!(!sanitize::staging_path(&folder.join("a.txt")).exists());
+        assert_eq!(
+            std::fs::read_to_string(folder.join("dir/c.txt")).unwrap(),
+            "hello"
+        );
+        assert_eq!(
+            std::fs::read_to_string(sanitize::staging_path(&folder.join("dir/c.txt"))).unwrap(),
+            "wor"
+        );
+
+        delete(&mut conn, 1, &folder, "dir/c.txt").unwrap();
+        assert!(!folder.join("dir/c.txt").exists());
+        assert!(!sanitize::staging_path(&folder.join("dir/c.txt")).exists());
+        assert!(DbFile::find_filename(&conn, "dir/c.txt").unwrap().is_none());
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn stages_only_marked_old_uploads() {
+        let (conn, folder) = setup("legacy", &[]);
+        let writer = write_conn(&folder);
+        for name in ["old.bin", "new.bin"] {
+            DbFile::new()
+                .with_filename(name)
+                .with_size(5)
+                .with_hash(String::new())
+                .with_inserted_by_id(1)
+                .build_val(&conn)
+                .unwrap();
+            std::fs::write(folder.join(name), "hello").unwrap();
+        }
+        // As the migration to staging leaves an upload it found unfinished
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (file_id) SELECT id FROM {} WHERE filename = 'old.bin'",
+                UnstagedUpload::TABLE_NAME,
+                DbFile::TABLE_NAME
+            ),
+            [],
+        )
+        .unwrap();
+
+        stage_old_uploads(&conn, &folder).unwrap();
+        assert!(!folder.join("old.bin").exists());
+        assert_eq!(
+            std::fs::read_to_string(sanitize::staging_path(&folder.join("old.bin"))).unwrap(),
+            "hello"
+        );
+        assert!(UnstagedUpload::files(&conn).unwrap().is_empty());
+
+        // Nothing marks this one, so whatever is under its own name isn't taken for its upload
+        upload(&conn, &writer, 1, &folder, "new.bin", b"hello").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(folder.join("new.bin")).unwrap(),
+            "hello"
+        );
+        assert_eq!(
+            std::fs::read(sanitize::staging_path(&folder.join("new.bin"))).unwrap(),
+            [0; 5]
+        );
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn renames_only_owned_files_and_never_over_another() {
+        let (mut conn, folder) = setup("rename", &["a.txt", "b.txt"]);
+
+        assert_eq!(
+            rename(&mut conn, 2, &folder, "a.txt", "c.txt").unwrap_err(),
+            "\"a.txt\" was uploaded by someone else"
+        );
+        assert_eq!(
+            rename(&mut conn, 1, &folder, "a.txt", "b.txt").unwrap_err(),
+            "\"b.txt\" already exists"
+        );
+        // Not in the database, but still on disk
+        std::fs::write(folder.join("stray.txt"), "stray").unwrap();
+        assert_eq!(
+            rename(&mut conn, 1, &folder, "a.txt", "stray.txt").unwrap_err(),
+            "\"stray.txt\" already exists"
+        );
+        assert_eq!(
+            std::fs::read_to_string(folder.join("b.txt")).unwrap(),
+            "hello"
+        );
+        assert_eq!(
+            std::fs::read_to_string(folder.join("stray.txt")).unwrap(),
+            "stray"
+        );
+
+        rename(&mut conn, 1, &folder, "a.txt", "dir/c.txt").unwrap();
+        assert!(!folder.join("a.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(folder.join("dir/c.txt")).unwrap(),
+            "hello"
+        );
+        assert!(DbFile::find_filename(&conn, "a.txt").unwrap().is_none());
+        assert!(DbFile::find_filename(&conn, "dir/c.txt").unwrap().is_some());
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
 }
@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, TcpStream},
+    panic::AssertUnwindSafe,
+    sync::{
+        Arc, Condvar, Mutex,
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    time::Duration,
+};
+
+use stable_ftp::logger;
+
+/// Connections waiting to be told the server is busy. Past this they're just closed
+const REJECT_QUEUE: usize = 16;
+
+/// How many connections the server takes on at once
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    /// Threads running sessions
+    pub workers: usize,
+    /// Accepted connections that may wait for a free worker
+    pub queue: usize,
+    /// Connections open at once, running or waiting
+    pub max_connections: usize,
+    /// Connections open at once from a single address
+    pub max_per_ip: usize,
+}
+
+/// How many connections are open, in total and from each address
+#[derive(Debug, Default)]
+struct Open {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// The connections that are open, and a way to wait on them all closing
+#[derive(Debug, Default)]
+struct Counts {
+    open: Mutex<Open>,
+    closed: Condvar,
+}
+
+/// Counts a connection as open until it's dropped
+pub struct Slot {
+    counts: Arc<Counts>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut open = self.counts.open.lock().unwrap();
+        open.total -= 1;
+        if open.total == 0 {
+            self.counts.closed.notify_all();
+        }
+        if let Some(count) = open.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Keeps count of the open connections, in total and from each address
+pub struct Admission {
+    max_connections: usize,
+    max_per_ip: usize,
+    counts: Arc<Counts>,
+}
+
+impl Admission {
+    pub fn new(max_connections: usize, max_per_ip: usize) -> Self {
+        Self {
+            max_connections,
+            max_per_ip,
+            counts: Arc::default(),
+        }
+    }
+
+    /// A slot for another connection from `ip`, or why there's no room for it
+    pub fn admit(&self, ip: IpAddr) -> Result<Slot, String> {
+        let mut open = self.counts.open.lock().unwrap();
+        let from_ip = open.per_ip.get(&ip).copied().unwrap_or_default();
+        if open.total >= self.max_connections {
+            Err("Too many connections to the server")?
+        }
+        if from_ip >= self.max_per_ip {
+            Err("Too many connections from your address")?
+        }
+        open.total += 1;
+        open.per_ip.insert(ip, from_ip + 1);
+        Ok(Slot {
+            counts: self.counts.clone(),
+            ip,
+        })
+    }
+
+    /// Waits up to `timeout` for every connection to close. `false` if some are still open
+    pub fn wait_closed(&self, timeout: Duration) -> bool {
+        let open = self.counts.open.lock().unwrap();
+        let (open, _) = self
+            .counts
+            .closed
+            .wait_timeout_while(open, timeout, |open| open.total > 0)
+            .unwrap();
+        open.total == 0
+    }
+}
+
+/// A fixed set of threads running sessions for the connections on a bounded queue. Connections
+/// past the limits go to a single thread that tells them to come back later
+pub struct Pool {
+    admission: Admission,
+    queue: SyncSender<(TcpStream, Slot)>,
+    rejects: SyncSender<(TcpStream, String)>,
+}
+
+/// Runs `work` on everything `receiver` gets until every sender is gone
+fn worker<T: Send + 'static>(receiver: Arc<Mutex<Receiver<T>>>, work: impl Fn(T) + Send + 'static) {
+    std::thread::spawn(move || {
+        loop {
+            // Only held while waiting, so the other workers can take the next one
+            let job = receiver.lock().unwrap().recv();
+            let Ok(job) = job else {
+                return;
+            };
+            // A session that panics already logged why, the worker carries on with the next one
+            let _ = std::panic::catch_unwind(AssertUnwindSafe(|| work(job)));
+        }
+    });
+}
+
+impl Pool {
+    /// Starts the workers, which run `session` on every connection let in, and the thread that
+    /// runs `reject` with the reason on every one turned away
+    pub fn new(
+        limits: ConnectionLimits,
+        session: impl Fn(TcpStream) + Send + Sync + 'static,
+        reject: impl Fn(TcpStream, String) + Send + 'static,
+    ) -> Self {
+        let (queue, receiver) = mpsc::sync_channel::<(TcpStream, Slot)>(limits.queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let session = Arc::new(session);
+        for _ in 0..limits.workers {
+            let session = session.clone();
+            // The slot goes with the connection, so it's freed once the session ends
+            worker(receiver.clone(), move |(tcp, _slot)| session(tcp));
+        }
+
+        let (rejects, receiver) = mpsc::sync_channel(REJECT_QUEUE);
+        worker(Arc::new(Mutex::new(receiver)), move |(tcp, reason)| {
+            reject(tcp, reason)
+        });
+
+        Self {
+            admission: Admission::new(limits.max_connections, limits.max_per_ip),
+            queue,
+            rejects,
+        }
+    }
+
+    /// Hands `tcp` to a worker, or turns it away if that would go past the limits
+    pub fn dispatch(&self, tcp: TcpStream) {
+        let ip = match tcp.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(err) => {
+                logger::warning(format!("Dropping a connection with no peer address: {err}"));
+                return;
+            }
+        };
+
+        let slot = match self.admission.admit(ip) {
+            Ok(slot) => slot,
+            Err(reason) => return self.reject(tcp, ip, reason),
+        };
+        match self.queue.try_send((tcp, slot)) {
+            Ok(()) => (),
+            Err(TrySendError::Full((tcp, slot)) | TrySendError::Disconnected((tcp, slot))) => {
+                drop(slot);
+                self.reject(tcp, ip, "Every worker is busy".to_string())
+            }
+        }
+    }
+
+    /// See [`Admission::wait_closed`]. Connections still waiting on a worker count too
+    pub fn wait_closed(&self, timeout: Duration) -> bool {
+        self.admission.wait_closed(timeout)
+    }
+
+    fn reject(&self, tcp: TcpStream, ip: IpAddr, reason: String) {
+        logger::warning(format!("Turning away {ip}: {reason}"));
+        if self.rejects.try_send((tcp, reason)).is_err() {
+            logger::warning(format!(
+                "Closing the connection from {ip} without a reply, too many are being turned away"
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, TcpListener},
+        sync::mpsc::Sender,
+    };
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn ip(last: u8) -> IpAddr {
+        Ipv4Addr::new(10, 0, 0, last).into()
+    }
+
+    /// The client and server ends of a new connection to `listener`
+    fn connection(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    /// A pool whose sessions say when they start and then wait to be let go, and that says why
+    /// it turned a connection away
+    fn pool(limits: ConnectionLimits) -> (Pool, Receiver<()>, Sender<()>, Receiver<String>) {
+        let (started, on_start) = mpsc::channel();
+        let (release, on_release) = mpsc::channel();
+        let on_release = Mutex::new(on_release);
+        let (rejected, on_reject) = mpsc::channel();
+        let pool = Pool::new(
+            limits,
+            move |_tcp| {
+                started.send(()).unwrap();
+                on_release.lock().unwrap().recv().unwrap();
+            },
+            move |_tcp, reason| rejected.send(reason).unwrap(),
+        );
+        (pool, on_start, release, on_reject)
+    }
+
+    #[test]
+    fn admits_up_to_the_limits() {
+        let admission = Admission::new(3, 2);
+        let first = admission.admit(ip(1)).unwrap();
+        let second = admission.admit(ip(1)).unwrap();
+        assert_eq!(
+            admission.admit(ip(1)).err().unwrap(),
+            "Too many connections from your address"
+        );
+        let third = admission.admit(ip(2)).unwrap();
+        assert_eq!(
+            admission.admit(ip(3)).err().unwrap(),
+            "Too many connections to the server"
+        );
+
+        // Dropping a slot makes room for another connection from the same address
+        drop(first);
+        let again = admission.admit(ip(1)).unwrap();
+        assert!(!admission.wait_closed(Duration::from_millis(10)));
+        drop((again, second, third));
+        assert!(admission.wait_closed(Duration::ZERO));
+        assert!(admission.counts.open.lock().unwrap().per_ip.is_empty());
+    }
+
+    #[test]
+    fn turns_away_past_the_queue() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (pool, on_start, release, on_reject) = pool(ConnectionLimits {
+            workers: 1,
+            queue: 1,
+            max_connections: 10,
+            max_per_ip: 10,
+        });
+
+        let (_running, tcp) = connection(&listener);
+        pool.dispatch(tcp);
+        on_start.recv_timeout(TIMEOUT).unwrap();
+        // The only worker is busy, so this one waits its turn and the next has nowhere to go
+        let (_waiting, tcp) = connection(&listener);
+        pool.dispatch(tcp);
+        let (_turned_away, tcp) = connection(&listener);
+        pool.dispatch(tcp);
+        assert_eq!(
+            on_reject.recv_timeout(TIMEOUT).unwrap(),
+            "Every worker is busy"
+        );
+
+        release.send(()).unwrap();
+        on_start.recv_timeout(TIMEOUT).unwrap();
+        release.send(()).unwrap();
+        assert!(pool.wait_closed(TIMEOUT));
+    }
+
+    #[test]
+    fn turns_away_past_the_limit_per_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (pool, on_start, release, on_reject) = pool(ConnectionLimits {
+            workers: 2,
+            queue: 2,
+            max_connections: 10,
+            max_per_ip: 1,
+        });
+
+        let (_running, tcp) = connection(&listener);
+        pool.dispatch(tcp);
+        on_start.recv_timeout(TIMEOUT).unwrap();
+        let (_turned_away, tcp) = connection(&listener);
+        pool.dispatch(tcp);
+        assert_eq!(
+            on_reject.recv_timeout(TIMEOUT).unwrap(),
+            "Too many connections from your address"
+        );
+
+        // Once the session ends its slot is free again
+        release.send(()).unwrap();
+        assert!(pool.wait_closed(TIMEOUT));
+        let (_next, tcp) = connection(&listener);
+        pool.dispatch(tcp);
+        on_start.recv_timeout(TIMEOUT).unwrap();
+        release.send(()).unwrap();
+        assert!(pool.wait_closed(TIMEOUT));
+    }
+}
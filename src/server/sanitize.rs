@@ -0,0 +1,207 @@
+use std::path::{Path, PathBuf};
+
+/// Longest name accepted for a single file or directory, the usual filesystem limit
+const MAX_COMPONENT_LEN: usize = 255;
+/// Longest whole relative name accepted
+const MAX_NAME_LEN: usize = 1024;
+/// Added to the name of an upload that's still coming in, it only gets its own name once it's
+/// complete and verified
+const STAGING_SUFFIX: &str = ".partial";
+/// Names Windows reserves for devices, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn check_component(component: &str) -> Result<(), String> {
+    if component == ".." {
+        Err("it leaves the target folder with `..`")?
+    }
+    if component.len() > MAX_COMPONENT_LEN {
+        Err(format!(
+            "\"{component}\" is longer than {MAX_COMPONENT_LEN} bytes"
+        ))?
+    }
+    if component.ends_with(['.', ' ']) {
+        Err(format!("\"{component}\" ends with a dot or a space"))?
+    }
+    if component.ends_with(STAGING_SUFFIX) {
+        Err(format!(
+            "\"{component}\" ends with {STAGING_SUFFIX}, which is kept for unfinished uploads"
+        ))?
+    }
+
+    let stem = component.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        Err(format!("\"{component}\" is a reserved name"))?
+    }
+    Ok(())
+}
+
+/// Turns a client supplied name into a plain relative one with `/` between directories.
+/// Empty and `.` parts are dropped, anything that could point outside the folder is refused
+pub fn normalize_name(name: &str) -> Result<String, String> {
+    let check = || {
+        if name.len() > MAX_NAME_LEN {
+            Err(format!("it is longer than {MAX_NAME_LEN} bytes"))?
+        }
+        if name.chars().any(char::is_control) {
+            Err("it contains NUL or other control characters")?
+        }
+        if name.contains('\\') {
+            Err("it contains a backslash, use `/` between directories")?
+        }
+        if name.starts_with('/')
+            || name
+                .split('/')
+                .next()
+                .is_some_and(|first| first.contains(':'))
+        {
+            Err("it is an absolute path")?
+        }
+
+        let components = name
+            .split('/')
+            .filter(|component| !component.is_empty() && *component != ".")
+            .collect::<Vec<_>>();
+        if components.is_empty() {
+            Err("it is empty")?
+        }
+        for component in &components {
+            check_component(component)?;
+        }
+        Ok(components.join("/"))
+    };
+    check().map_err(|reason: String| format!("Invalid file name \"{name}\": {reason}"))
+}
+
+/// Where a file named `name` goes under `target_folder`. Symlinks along the way are refused so
+/// nothing in the folder can redirect a write somewhere else
+pub fn resolve(target_folder: &Path, name: &str) -> Result<(String, PathBuf), String> {
+    let name = normalize_name(name)?;
+    let refuse = |path: &Path| -> Result<(), String> {
+        Err(format!(
+            "Invalid file name \"{name}\": \"{}\" is a symlink",
+            path.display()
+        ))
+    };
+
+    let mut path = target_folder.to_path_buf();
+    for component in name.split('/') {
+        path.push(component);
+        match path.symlink_metadata() {
+            Ok(meta) if meta.file_type().is_symlink() => refuse(&path)?,
+            Ok(_) => (),
+            // Nothing from here on exists yet, so nothing can be a symlink
+            Err(_) => break,
+        }
+    }
+    // Uploads are written under their staging name first
+    let path = target_folder.join(&name);
+    let staging = staging_path(&path);
+    if staging
+        .symlink_metadata()
+        .is_ok_and(|meta| meta.file_type().is_symlink())
+    {
+        refuse(&staging)?
+    }
+    Ok((name, path))
+}
+
+/// Where the file that goes at `file_path` is written until it's complete and verified, so the
+/// target folder only ever shows finished files
+pub fn staging_path(file_path: &Path) -> PathBuf {
+    let mut path = file_path.as_os_str().to_owned();
+    path.push(STAGING_SUFFIX);
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_relative_names() {
+        assert_eq!(normalize_name("file.bin").unwrap(), "file.bin");
+        assert_eq!(normalize_name("a//./b/c.txt").unwrap(), "a/b/c.txt");
+        assert_eq!(normalize_name("./build/out.o").unwrap(), "build/out.o");
+        assert_eq!(normalize_name("a.b/..c").unwrap(), "a.b/..c");
+    }
+
+    #[test]
+    fn stages_next_to_the_file() {
+        assert_eq!(
+            staging_path(Path::new("ingress/dir/a.bin")),
+            Path::new("ingress/dir/a.bin.partial")
+        );
+        // The staging name of any accepted name is itself refused, so the two can't collide
+        let staged = staging_path(Path::new("a.bin"));
+        assert!(normalize_name(staged.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn rejects_escaping_names() {
+        for name in [
+            "../../etc/cron.d/x",
+            "a/../../b",
+            "/etc/passwd",
+            "C:/Windows/x",
+            "c:x",
+            "a\\..\\b",
+            "",
+            "./",
+            "bad\0name",
+            "tab\there",
+        ] {
+            assert!(normalize_name(name).is_err(), "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_and_long_names() {
+        for name in [
+            "CON",
+            "dir/nul.txt",
+            "Com1.tar.gz",
+            "lpt9",
+            "trailing.",
+            "space ",
+            "upload.bin.partial",
+            "dir.partial/file",
+        ] {
+            assert!(normalize_name(name).is_err(), "{name:?} was accepted");
+        }
+        assert!(normalize_name("console.log").is_ok());
+        assert!(normalize_name("partial.bin").is_ok());
+
+        assert!(normalize_name(&"a".repeat(MAX_COMPONENT_LEN)).is_ok());
+        assert!(normalize_name(&"a".repeat(MAX_COMPONENT_LEN + 1)).is_err());
+        let long = vec!["a".repeat(100); MAX_NAME_LEN / 100 + 1].join("/");
+        assert!(normalize_name(&long).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks() {
+        let root = std::env::temp_dir().join(format!("stable-ftp-sanitize-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("real")).unwrap();
+        std::os::unix::fs::symlink("/tmp", root.join("link")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", root.join("real/file")).unwrap();
+
+        assert_eq!(
+            resolve(&root, "real/./new.bin").unwrap(),
+            ("real/new.bin".to_string(), root.join("real/new.bin"))
+        );
+        assert!(resolve(&root, "not/there/yet.bin").is_ok());
+        assert!(resolve(&root, "link/x").is_err());
+        assert!(resolve(&root, "real/file").is_err());
+        std::os::unix::fs::symlink("/etc/passwd", root.join("real/staged.bin.partial")).unwrap();
+        assert!(resolve(&root, "real/staged.bin").is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
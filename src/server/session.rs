@@ -0,0 +1,500 @@
+use std::{collections::VecDeque, error::Error, fs::File, ops::Range, path::PathBuf};
+
+use rusqlite::Connection;
+use stable_ftp::{
+    Capabilities, Envelope, Message,
+    db::{DbFile, get_write_connection},
+    file_size_text,
+    logger::{self, Loggable},
+    num_packets,
+    structs::{
+        DownloadParts, DownloadResponse, FileDescription, FileDescriptionResponse, FilePart,
+        FilePartResponse, FileStatus, FileStatusEnum, Id, MessageType, ResumeDecision,
+    },
+    to_hex,
+};
+
+use crate::{
+    PartOutcome, Upload, open_download, open_upload, restart_upload, shutdown, upload_status,
+};
+
+/// What a runtime does after a step of an [`Exchange`]
+pub struct Step {
+    /// Sent to the client in order, before anything else
+    pub replies: Vec<Envelope>,
+    pub next: Next,
+}
+
+pub enum Next {
+    /// Wait for the client's next message, the next step gets it
+    Recv,
+    /// Take the next step right away, there's more to send
+    Send,
+    /// Over, the session goes on with the client's next request
+    Done,
+    /// Something went wrong that the session can't go on from
+    Fail(String),
+}
+
+impl Step {
+    fn new(replies: Vec<Envelope>, next: Next) -> Self {
+        Self { replies, next }
+    }
+
+    fn reply(reply: impl Message, next: Next) -> Self {
+        Self::new(vec![Envelope::new(reply)], next)
+    }
+}
+
+/// An upload or download as the protocol has it, the same whichever runtime serves it. The
+/// runtimes only do the sending and recieving. Steps can take a while, so they're run wherever
+/// blocking is fine with heartbeats going out meanwhile
+pub trait Exchange: Send + 'static {
+    /// Works out what to send and what comes next. `received` is the client's message if the last
+    /// step waited for one, the first step gets the request that started it all
+    fn step(&mut self, read_conn: &Connection, received: Option<Envelope>) -> Step;
+}
+
+/// How much of the rate limits `envelope` uses up, only parts of files count
+pub fn throttled_bytes(envelope: &Envelope) -> Option<u64> {
+    match envelope.message_type() {
+        Some(MessageType::FilePart) => Some(envelope.payload.len() as u64),
+        _ => None,
+    }
+}
+
+/// The status a client needs to pick up wherever the file left off, sending parts of
+/// `packet_size`
+fn file_status(
+    read_conn: &Connection,
+    file: &mut File,
+    db_file: &DbFile,
+    packet_size: u64,
+) -> Result<FileStatus, Box<dyn Error>> {
+    let missing = db_file.missing_ranges(read_conn)?;
+    let status = upload_status(file, db_file, missing, packet_size)
+        .with_warning("Failed to hash the already recieved parts of the file")?;
+    Ok(status)
+}
+
+/// An upload the client was told the status of, waiting on whether it goes on
+struct Deciding {
+    file: File,
+    db_file: DbFile,
+    description: FileDescription,
+}
+
+enum UploadStage {
+    /// Waiting on the [`FileDescription`]
+    Describing,
+    Deciding(Deciding),
+    /// Parts are coming in, `left` more bytes of them
+    Receiving {
+        upload: Upload,
+        left: u64,
+    },
+    Over,
+}
+
+/// Receives a file, starting from its [`FileDescription`]. Anything the client can recover from
+/// is sent back to it, a [`Next::Fail`] means the session can't go on
+pub struct UploadSession {
+    user_id: Id,
+    target_folder: PathBuf,
+    agreed: Capabilities,
+    stage: UploadStage,
+}
+
+impl UploadSession {
+    pub fn new(user_id: Id, target_folder: PathBuf, agreed: Capabilities) -> Self {
+        Self {
+            user_id,
+            target_folder,
+            agreed,
+            stage: UploadStage::Describing,
+        }
+    }
+
+    /// The client hears why, and can go on to its next file
+    fn refuse(err: impl ToString) -> Step {
+        Step::reply(
+            FileDescriptionResponse::FailMessage(err.to_string()),
+            Next::Done,
+        )
+    }
+
+    /// Tells the client what's already there, then waits on what it makes of it
+    fn tell_status(&mut self, deciding: Deciding, status: FileStatus) -> Step {
+        if let FileStatusEnum::Exists = status.get_status() {
+            logger::info(format!(
+                "\"{}\" already exists, nothing to recieve",
+                deciding.db_file.filename
+            ));
+            return Step::reply(FileDescriptionResponse::Status(status), Next::Done);
+        }
+        self.stage = UploadStage::Deciding(deciding);
+        Step::reply(FileDescriptionResponse::Status(status), Next::Recv)
+    }
+
+    fn describe(&mut self, read_conn: &Connection, envelope: Envelope) -> Step {
+        let opened = envelope.open::<FileDescription>().and_then(|description| {
+            let (mut file, db_file) = open_upload(
+                read_conn,
+                get_write_connection(),
+                self.user_id,
+                &self.target_folder,
+                &self.agreed,
+                &description,
+            )?;
+            let status = file_status(read_conn, &mut file, &db_file, description.packet_size)?;
+            Ok((status, file, db_file, description))
+        });
+        match opened {
+            Ok((status, file, db_file, description)) => {
+                let deciding = Deciding {
+                    file,
+                    db_file,
+                    description,
+                };
+                self.tell_status(deciding, status)
+            }
+            Err(err) => Self::refuse(err),
+        }
+    }
+
+    fn decide(&mut self, read_conn: &Connection, deciding: Deciding, envelope: Envelope) -> Step {
+        let Deciding {
+            file,
+            db_file,
+            description,
+        } = deciding;
+        match envelope.open::<ResumeDecision>() {
+            Ok(ResumeDecision::Resume(bytes)) => {
+                let upload = Upload::new(
+                    file,
+                    db_file,
+                    description.packet_size,
+                    &self.target_folder,
+                    &self.agreed,
+                );
+                self.receive_more(upload, bytes, Vec::new())
+            }
+            Ok(ResumeDecision::Restart) => {
+                let restarted = restart_upload(
+                    &file,
+                    get_write_connection(),
+                    db_file,
+                    self.user_id,
+                    to_hex(&description.hash),
+                    description.size,
+                )
+                .and_then(|db_file| {
+                    let mut file = file;
+                    let status =
+                        file_status(read_conn, &mut file, &db_file, description.packet_size)?;
+                    Ok((status, file, db_file))
+                });
+                match restarted {
+                    Ok((status, file, db_file)) => {
+                        let deciding = Deciding {
+                            file,
+                            db_file,
+                            description,
+                        };
+                        self.tell_status(deciding, status)
+                    }
+                    Err(err) => Self::refuse(err),
+                }
+            }
+            Ok(ResumeDecision::Abort) => Self::refuse(format!(
+                "Client's copy of \"{}\" no longer matches, not resuming",
+                db_file.filename
+            )),
+            Err(err) => Self::refuse(err),
+        }
+    }
+
+    fn receive(&mut self, mut upload: Upload, left: u64, envelope: Envelope) -> Step {
+        let part = match envelope.open::<FilePart>() {
+            Ok(part) => part,
+            Err(err) => return Self::fail(Vec::new(), err),
+        };
+        let offset = part.offset;
+        match upload.store(part) {
+            Ok(PartOutcome::Stored(len)) => {
+                let reply = Envelope::new(FilePartResponse::Success(offset));
+                self.receive_more(upload, left.saturating_sub(len), vec![reply])
+            }
+            Ok(PartOutcome::Resend) => {
+                let reply = Envelope::new(FilePartResponse::Resend(offset));
+                self.receive_more(upload, left, vec![reply])
+            }
+            Err(err) => Self::fail(Vec::new(), err),
+        }
+    }
+
+    /// Waits on the next part, or tells the client how the upload stands once it sent everything
+    /// it was going to
+    fn receive_more(&mut self, upload: Upload, left: u64, mut replies: Vec<Envelope>) -> Step {
+        if left == 0 {
+            return match upload.result() {
+                Ok(result) => {
+                    replies.push(Envelope::new(result));
+                    Step::new(replies, Next::Done)
+                }
+                Err(err) => Self::fail(replies, err),
+            };
+        }
+        // Every part acknowledged so far is committed, the client sends the rest once we're back
+        if shutdown::requested() {
+            return Step::new(replies, Next::Done);
+        }
+        self.stage = UploadStage::Receiving { upload, left };
+        Step::new(replies, Next::Recv)
+    }
+
+    fn fail(mut replies: Vec<Envelope>, err: impl ToString) -> Step {
+        let err = err.to_string();
+        logger::warning(format!("Failed to recieve the upload: {err}"));
+        replies.push(Envelope::new(FilePartResponse::Failure(err.clone())));
+        Step::new(replies, Next::Fail(err))
+    }
+}
+
+impl Exchange for UploadSession {
+    fn step(&mut self, read_conn: &Connection, received: Option<Envelope>) -> Step {
+        let stage = std::mem::replace(&mut self.stage, UploadStage::Over);
+        match (stage, received) {
+            (UploadStage::Describing, Some(envelope)) => self.describe(read_conn, envelope),
+            (UploadStage::Deciding(deciding), Some(envelope)) => {
+                self.decide(read_conn, deciding, envelope)
+            }
+            (UploadStage::Receiving { upload, left }, Some(envelope)) => {
+                self.receive(upload, left, envelope)
+            }
+            (UploadStage::Over, _) | (_, None) => {
+                Step::new(Vec::new(), Next::Fail("The upload is already over".into()))
+            }
+        }
+    }
+}
+
+/// A file being sent, in whichever parts the client still needs
+struct Sending {
+    file: File,
+    buf: Vec<u8>,
+    packet_size: u64,
+    total_packets: u64,
+    /// Parts asked for that haven't gone out yet
+    queue: VecDeque<Range<u64>>,
+}
+
+enum DownloadStage {
+    /// Waiting on the [`stable_ftp::structs::DownloadRequest`]
+    Requested,
+    Sending(Sending),
+    Over,
+}
+
+/// Sends back the file a client asks for, in whichever parts it says it still needs
+pub struct DownloadSession {
+    target_folder: PathBuf,
+    agreed: Capabilities,
+    stage: DownloadStage,
+}
+
+impl DownloadSession {
+    pub fn new(target_folder: PathBuf, agreed: Capabilities) -> Self {
+        Self {
+            target_folder,
+            agreed,
+            stage: DownloadStage::Requested,
+        }
+    }
+
+    fn open(&mut self, read_conn: &Connection, envelope: Envelope) -> Step {
+        let (file, file_description) =
+            match open_download(read_conn, &self.target_folder, &self.agreed, envelope) {
+                Ok(found) => found,
+                Err(err) => {
+                    return Step::reply(DownloadResponse::FailMessage(err.to_string()), Next::Done);
+                }
+            };
+
+        logger::info(format!(
+            "Sending \"{}\" with size {}",
+            file_description.name,
+            file_size_text(file_description.size)
+        ));
+        let packet_size = file_description.packet_size;
+        self.stage = DownloadStage::Sending(Sending {
+            file,
+            buf: vec![0; packet_size as usize],
+            packet_size,
+            total_packets: num_packets(packet_size, file_description.size),
+            queue: VecDeque::new(),
+        });
+        Step::reply(DownloadResponse::File(file_description), Next::Recv)
+    }
+
+    /// Queues the parts the client asks for next. Asking for none means it has all of them
+    fn request(&mut self, mut sending: Sending, envelope: Envelope) -> Step {
+        let DownloadParts { parts } = match envelope.open() {
+            Ok(parts) => parts,
+            Err(err) => return Step::new(Vec::new(), Next::Fail(err.to_string())),
+        };
+        if parts.is_empty() {
+            return Step::new(Vec::new(), Next::Done);
+        }
+
+        for range in parts {
+            if range.end > sending.total_packets {
+                return Step::new(
+                    Vec::new(),
+                    Next::Fail(format!(
+                        "Part Num: {} is past the last part ({})",
+                        range.end - 1,
+                        sending.total_packets.saturating_sub(1)
+                    )),
+                );
+            }
+            if range.start < range.end {
+                sending.queue.push_back(range.start..range.end);
+            }
+        }
+        self.send(sending)
+    }
+
+    /// Sends the next part in the queue, or waits on the client's next request once there's none
+    fn send(&mut self, mut sending: Sending) -> Step {
+        let Some(range) = sending.queue.front_mut() else {
+            self.stage = DownloadStage::Sending(sending);
+            return Step::new(Vec::new(), Next::Recv);
+        };
+        // The client asks for whatever it didn't get once the server is back
+        if shutdown::requested() {
+            return Step::new(Vec::new(), Next::Done);
+        }
+
+        let part_num = range.start;
+        range.start += 1;
+        if range.is_empty() {
+            sending.queue.pop_front();
+        }
+        let part = FilePart::read(
+            &mut sending.file,
+            &mut sending.buf,
+            part_num * sending.packet_size,
+            self.agreed.compression(),
+            self.agreed.checksum(),
+        );
+        let part = match part {
+            Ok(part) => part,
+            Err(err) => return Step::new(Vec::new(), Next::Fail(err.to_string())),
+        };
+        let next = match sending.queue.is_empty() {
+            true => Next::Recv,
+            false => Next::Send,
+        };
+        self.stage = DownloadStage::Sending(sending);
+        Step::reply(part, next)
+    }
+}
+
+impl Exchange for DownloadSession {
+    fn step(&mut self, read_conn: &Connection, received: Option<Envelope>) -> Step {
+        let stage = std::mem::replace(&mut self.stage, DownloadStage::Over);
+        match (stage, received) {
+            (DownloadStage::Requested, Some(envelope)) => self.open(read_conn, envelope),
+            (DownloadStage::Sending(sending), Some(envelope)) => self.request(sending, envelope),
+            (DownloadStage::Sending(sending), None) => self.send(sending),
+            (DownloadStage::Requested | DownloadStage::Over, _) => Step::new(
+                Vec::new(),
+                Next::Fail("The download is already over".into()),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use stable_ftp::{
+        MIN_PACKET_SIZE, db,
+        structs::{DownloadRequest, PartRange},
+    };
+    use typed_db::DbTable;
+
+    use super::*;
+
+    #[test]
+    fn sends_the_parts_asked_for() {
+        let folder =
+            std::env::temp_dir().join(format!("stable-ftp-session-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        let conn = Connection::open(folder.join("stable-ftp.sqlite")).unwrap();
+        db::init(&conn).unwrap();
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (id, token) VALUES (1, 'one')",
+                db::UserAuth::TABLE_NAME
+            ),
+            [],
+        )
+        .unwrap();
+        let size = MIN_PACKET_SIZE + 1;
+        DbFile::new()
+            .with_filename("a.bin")
+            .with_size(size)
+            .with_hash(String::new())
+            .with_inserted_by_id(1)
+            .build_val(&conn)
+            .unwrap()
+            .set_verified(&conn, true)
+            .unwrap();
+        std::fs::write(folder.join("a.bin"), vec![7; size as usize]).unwrap();
+
+        let mut download = DownloadSession::new(folder.clone(), Capabilities::default());
+        let request = Envelope::new(DownloadRequest {
+            name: "a.bin".into(),
+            packet_size: MIN_PACKET_SIZE,
+        });
+        let mut step = download.step(&conn, Some(request));
+        assert!(matches!(step.next, Next::Recv));
+        match step.replies.remove(0).open::<DownloadResponse>().unwrap() {
+            DownloadResponse::File(description) => assert_eq!(description.size, size),
+            DownloadResponse::FailMessage(err) => panic!("{err}"),
+        }
+
+        // One part a step, until there's none left to send
+        let parts = |parts| Some(Envelope::new(DownloadParts { parts }));
+        let mut step = download.step(&conn, parts(vec![PartRange { start: 0, end: 2 }]));
+        assert!(matches!(step.next, Next::Send));
+        let part: FilePart = step.replies.remove(0).open().unwrap();
+        assert_eq!((part.offset, part.data.len() as u64), (0, MIN_PACKET_SIZE));
+        let mut step = download.step(&conn, None);
+        assert!(matches!(step.next, Next::Recv));
+        let part: FilePart = step.replies.remove(0).open().unwrap();
+        assert_eq!((part.offset, part.data.len()), (MIN_PACKET_SIZE, 1));
+
+        let past_the_end = parts(vec![PartRange { start: 1, end: 3 }]);
+        let step = download.step(&conn, past_the_end);
+        assert!(matches!(step.next, Next::Fail(_)));
+        assert!(step.replies.is_empty());
+
+        // A file that isn't there is refused, and the client can go on
+        let mut download = DownloadSession::new(folder.clone(), Capabilities::default());
+        let request = Envelope::new(DownloadRequest {
+            name: "b.bin".into(),
+            packet_size: MIN_PACKET_SIZE,
+        });
+        let mut step = download.step(&conn, Some(request));
+        assert!(matches!(step.next, Next::Done));
+        assert!(matches!(
+            step.replies.remove(0).open::<DownloadResponse>().unwrap(),
+            DownloadResponse::FailMessage(_)
+        ));
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+}
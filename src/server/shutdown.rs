@@ -0,0 +1,88 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{self, TcpStream},
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+use stable_ftp::{logger, structs::Busy};
+use tokio::sync::watch;
+
+/// Whether the server's been asked to stop, and the sessions that need waking up when it is
+struct State {
+    requested: AtomicBool,
+    /// Seconds clients are told to wait before coming back
+    retry_after: AtomicU64,
+    /// Sockets of the sessions waiting on their client's next request
+    idle: Mutex<HashMap<u64, TcpStream>>,
+    next_idle: AtomicU64,
+    /// For sessions on the event loop to wait on
+    stopping: watch::Sender<bool>,
+}
+
+static STATE: LazyLock<State> = LazyLock::new(|| State {
+    requested: AtomicBool::new(false),
+    retry_after: AtomicU64::new(0),
+    idle: Mutex::default(),
+    next_idle: AtomicU64::new(0),
+    stopping: watch::Sender::new(false),
+});
+
+/// Shuts the server down on SIGINT or SIGTERM, telling clients to come back after `retry_after`
+/// seconds. Asking a second time exits right away
+pub fn install(retry_after: u64) -> Result<(), ctrlc::Error> {
+    STATE.retry_after.store(retry_after, Ordering::SeqCst);
+    ctrlc::set_handler(|| {
+        if requested() {
+            logger::warning("Asked to stop again, exiting without waiting on the sessions");
+            std::process::exit(1);
+        }
+        logger::info("Shutting down, letting the parts in flight finish");
+        request();
+    })
+}
+
+pub fn requested() -> bool {
+    STATE.requested.load(Ordering::SeqCst)
+}
+
+/// Stops taking on new connections and wakes every session waiting on its client, so they can
+/// all say goodbye
+pub fn request() {
+    STATE.requested.store(true, Ordering::SeqCst);
+    STATE.stopping.send_replace(true);
+    for tcp in STATE.idle.lock().unwrap().values() {
+        let _ = tcp.shutdown(net::Shutdown::Read);
+    }
+}
+
+/// What clients are told when the server is going away
+pub fn busy() -> Busy {
+    Busy {
+        retry_after: STATE.retry_after.load(Ordering::SeqCst),
+        reason: "Server is shutting down".to_string(),
+    }
+}
+
+/// Runs `wait`, which reads the client's next request off `tcp`. A shutdown cuts the read short
+/// rather than waiting out the idle timeout
+pub fn while_idle<T>(tcp: &TcpStream, wait: impl FnOnce() -> T) -> io::Result<T> {
+    let id = STATE.next_idle.fetch_add(1, Ordering::SeqCst);
+    STATE.idle.lock().unwrap().insert(id, tcp.try_clone()?);
+    // The shutdown may have gone through the idle sessions just before this one joined them
+    if requested() {
+        let _ = tcp.shutdown(net::Shutdown::Read);
+    }
+    let out = wait();
+    STATE.idle.lock().unwrap().remove(&id);
+    Ok(out)
+}
+
+/// Finishes once a shutdown is requested
+pub async fn stopping() {
+    let mut stopping = STATE.stopping.subscribe();
+    let _ = stopping.wait_for(|&stop| stop).await;
+}
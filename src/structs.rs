@@ -2,6 +2,55 @@ use lazy_marshal::prelude::*;
 
 pub type Id = i32;
 
+/// Tags every message on the wire. Each one is framed as this type as a little endian `u32`,
+/// the payload length as a little endian `u64`, then the marshalled payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MessageType {
+    AuthRequest = 1,
+    AuthResponse = 2,
+    FileDescription = 3,
+    FileDescriptionResponse = 4,
+    ResumeDecision = 5,
+    FilePart = 6,
+    FilePartResponse = 7,
+    UploadResult = 8,
+    Error = 9,
+    DownloadRequest = 10,
+    DownloadResponse = 11,
+    DownloadParts = 12,
+    ListRequest = 13,
+    ListResponse = 14,
+    DeleteRequest = 15,
+    RenameRequest = 16,
+    FileOpResponse = 17,
+    Heartbeat = 18,
+    Busy = 19,
+}
+
+/// Sent by a peer that's busy with something slow so the other side knows it's still there.
+/// Can come between any two messages and is dropped on arrival
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub struct Heartbeat {
+    /// Counts up from 0 over each stretch of heartbeats
+    pub seq: u64,
+}
+
+/// Sent by the server in place of an [`AuthResponse`] when it has no room for another connection,
+/// or in place of any reply when it's shutting down. It hangs up right after
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub struct Busy {
+    /// Seconds to wait before connecting again
+    pub retry_after: u64,
+    pub reason: String,
+}
+
+/// Sent in place of a reply to a message the peer couldn't handle, like one with an unknown type
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub struct ErrorMessage {
+    pub message: String,
+}
+
 #[derive(Debug, Clone, Copy, Marshal, UnMarshal)]
 pub struct Version {
     pub major: u32,
@@ -11,14 +60,62 @@ pub struct Version {
 
 #[derive(Debug, Clone, Marshal, UnMarshal)]
 pub struct AuthRequest {
+    /// Only for the logs, what the peers can do is settled by their capabilities
     pub version: Version,
     pub token: String,
+    /// What the client supports, most preferred first
+    pub capabilities: Vec<Capability>,
 }
 
 #[derive(Debug, Clone, Marshal, UnMarshal)]
 pub struct AuthResponse {
     pub success: bool,
     pub failure_reason: String,
+    /// What both sides support, in the client's order. The first of each is used for the rest of
+    /// the connection
+    pub capabilities: Vec<Capability>,
+}
+
+/// Something a peer supports, as a [`CapabilityKind`] and its values. Kinds a peer doesn't know
+/// are skipped, so new ones can be added without breaking older peers
+#[derive(Debug, Clone, PartialEq, Eq, Marshal, UnMarshal)]
+pub struct Capability {
+    pub kind: u32,
+    pub values: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CapabilityKind {
+    /// [`Compression`] codecs
+    Compression = 1,
+    /// [`Checksum`] algorithms for the parts
+    Checksum = 2,
+    /// Most parts that may wait on an acknowledgement at once
+    Window = 3,
+    /// Largest part size accepted
+    MaxPacketSize = 4,
+    /// How often the peer wants to hear something while we're busy, in milliseconds. A peer that
+    /// doesn't send this doesn't understand [`Heartbeat`]s
+    Heartbeat = 5,
+    /// Smallest part size accepted, besides the last part of a file
+    MinPacketSize = 6,
+    /// Revisions of the wire protocol spoken, see [`crate::PROTOCOL_REVISION`]
+    Protocol = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Marshal, UnMarshal)]
+pub enum Compression {
+    None,
+    Zstd,
+    Deflate,
+}
+
+/// How each [`FilePart`] is checked for corruption in transit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Marshal, UnMarshal)]
+pub enum Checksum {
+    Crc32c,
+    Xxh3,
 }
 
 #[derive(Debug, Clone, Marshal, UnMarshal)]
@@ -26,6 +123,8 @@ pub struct FileDescription {
     pub name: String,
     pub size: u64,
     pub packet_size: u64,
+    /// SHA-256 digest of the whole source file
+    pub hash: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Copy, Marshal, UnMarshal)]
@@ -39,9 +138,28 @@ pub enum FileStatusEnum {
 pub struct FileStatus {
     pub id: Id,
     pub status: FileStatusEnum,
-    pub request_packet: u64,
+    /// How many bytes of the file the server already has
+    pub received_bytes: u64,
+    /// The most a single part may hold on this connection, whatever earlier attempts used
     pub packet_size: u64,
-    pub total_packets: u64,
+    /// SHA-256 over every byte the server already has, in file order
+    pub received_hash: Vec<u8>,
+    /// Every byte range the server still needs
+    pub missing: Vec<ByteRange>,
+}
+
+/// The parts `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Marshal, UnMarshal)]
+pub struct PartRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The bytes `start..end` of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Marshal, UnMarshal)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
 }
 
 #[derive(Debug, Clone, Marshal, UnMarshal)]
@@ -50,14 +168,128 @@ pub enum FileDescriptionResponse {
     FailMessage(String),
 }
 
+/// Sent by the client after every [`FileStatus`]. For a [`FileStatusEnum::Resumeable`] file
+/// this comes once it has compared [`FileStatus::received_hash`] against its own copy
+#[derive(Debug, Clone, Copy, Marshal, UnMarshal)]
+pub enum ResumeDecision {
+    /// Go ahead, this connection is going to send this many bytes, in parts of any size up to
+    /// [`FileStatus::packet_size`]
+    Resume(u64),
+    /// Throw away what the server has and start over, answered with a fresh [`FileDescriptionResponse`]
+    Restart,
+    /// Drop the upload, answered with a [`FileDescriptionResponse::FailMessage`]
+    Abort,
+}
+
 #[derive(Debug, Clone, Marshal, UnMarshal)]
 pub struct FilePart {
-    pub part_num: u64,
+    /// Where in the file `data` goes once decompressed
+    pub offset: u64,
+    /// Checksum of `data` as it was sent, with the [`Checksum`] agreed on in the [`AuthResponse`]
+    pub checksum: u64,
+    /// Either [`Compression::None`] or whatever was agreed on in the [`AuthResponse`]
+    pub compression: Compression,
     pub data: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Marshal, UnMarshal)]
-pub struct FilePartResponse {
-    pub success: bool,
-    pub message: String,
+pub enum FilePartResponse {
+    /// The part at this offset is written and committed
+    Success(u64),
+    /// The part at this offset failed its checksum and needs to be sent again
+    Resend(u64),
+    Failure(String),
+}
+
+/// Asks for a file on the server back
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub struct DownloadRequest {
+    pub name: String,
+    /// Size of the parts to send it in
+    pub packet_size: u64,
+}
+
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub enum DownloadResponse {
+    /// The file is there and will come in parts of [`FileDescription::packet_size`]
+    File(FileDescription),
+    FailMessage(String),
+}
+
+/// The parts of a download the client still needs, each answered with a [`FilePart`] at the
+/// part number times [`FileDescription::packet_size`]. An empty list ends the download
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub struct DownloadParts {
+    pub parts: Vec<PartRange>,
+}
+
+/// Asks what files are on the server. Empty strings don't filter anything
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub struct ListRequest {
+    /// Only files whose name starts with this
+    pub prefix: String,
+    pub state: ListFilter,
+    /// Only files uploaded by the user with these notes or this id
+    pub uploader: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Marshal, UnMarshal)]
+pub enum ListFilter {
+    All,
+    /// Uploaded and verified
+    Complete,
+    /// Still missing parts, or failed verification
+    Incomplete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Marshal, UnMarshal)]
+pub enum UploadState {
+    Uploading,
+    Complete,
+    Corrupt,
+}
+
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub struct ListEntry {
+    pub name: String,
+    pub size: u64,
+    /// How many bytes of it the server has
+    pub received_bytes: u64,
+    pub state: UploadState,
+    /// Unix timestamp in seconds of when the upload started
+    pub created_date: i64,
+    pub uploader: String,
+}
+
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub enum ListResponse {
+    Entries(Vec<ListEntry>),
+    FailMessage(String),
+}
+
+/// Removes a file the user uploaded, answered with a [`FileOpResponse`]
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub struct DeleteRequest {
+    pub name: String,
+}
+
+/// Moves a file the user uploaded to a new name, answered with a [`FileOpResponse`]
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub struct RenameRequest {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub enum FileOpResponse {
+    Done,
+    FailMessage(String),
+}
+
+#[derive(Debug, Clone, Marshal, UnMarshal)]
+pub enum UploadResult {
+    Verified,
+    Corrupt(String),
+    /// Other connections are still working on the file, this many bytes are still missing
+    Pending(u64),
 }
@@ -0,0 +1,257 @@
+use std::{io, net::TcpStream, path::Path, sync::Arc};
+
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{StreamIterator, async_stream::AsyncStream, to_hex};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Box<dyn std::error::Error>> {
+    let certs = CertificateDer::pem_file_iter(path)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        Err(format!("No certificates found in {}", path.display()))?
+    }
+    Ok(certs)
+}
+
+/// Hex encoded SHA-256 of the certificate, which is what a client pins with `--tls-pin`
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    to_hex(&Sha256::digest(cert))
+}
+
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error>> {
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, PrivateKeyDer::from_pem_file(key)?)?;
+    Ok(Arc::new(config))
+}
+
+/// Trusts the CAs in the `ca` bundle, or only the certificate with the `pin` fingerprint if given
+pub fn client_config(
+    ca: Option<&Path>,
+    pin: Option<&str>,
+) -> Result<Arc<ClientConfig>, Box<dyn std::error::Error>> {
+    let builder =
+        ClientConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+
+    let config = match (pin, ca) {
+        (Some(pin), _) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCert {
+                fingerprint: pin.replace(':', "").to_lowercase(),
+                provider: provider(),
+            }))
+            .with_no_client_auth(),
+        (None, Some(ca)) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots.add(cert)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        (None, None) => Err("Either a CA bundle or a pinned certificate is needed for TLS")?,
+    };
+    Ok(Arc::new(config))
+}
+
+/// Runs the server side of the handshake so failures show up before any messages are read
+pub fn accept(
+    config: Arc<ServerConfig>,
+    mut tcp: TcpStream,
+) -> Result<StreamIterator, Box<dyn std::error::Error>> {
+    let mut conn = ServerConnection::new(config)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut tcp)?;
+    }
+    Ok(StreamIterator(Box::new(StreamOwned::new(conn, tcp))))
+}
+
+/// [`accept`] for connections run on an event loop
+pub async fn accept_async(
+    config: Arc<ServerConfig>,
+    tcp: tokio::net::TcpStream,
+) -> io::Result<AsyncStream> {
+    let stream = tokio_rustls::TlsAcceptor::from(config).accept(tcp).await?;
+    Ok(AsyncStream::new(stream))
+}
+
+pub fn connect(
+    config: Arc<ClientConfig>,
+    server_name: &str,
+    mut tcp: TcpStream,
+) -> Result<StreamIterator, Box<dyn std::error::Error>> {
+    let mut conn = ClientConnection::new(config, ServerName::try_from(server_name.to_string())?)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut tcp)?;
+    }
+    Ok(StreamIterator(Box::new(StreamOwned::new(conn, tcp))))
+}
+
+/// Accepts exactly one certificate no matter who signed it, so self-signed servers can be used
+#[derive(Debug)]
+struct PinnedCert {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(end_entity);
+        match fingerprint == self.fingerprint {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General(format!(
+                "Server certificate {fingerprint} doesn't match the pinned {}",
+                self.fingerprint
+            ))),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, net::TcpListener, path::PathBuf, time::Duration};
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+
+    use super::*;
+    use crate::structs::DeleteRequest;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Writes `cert` and its key out as PEM files under `dir`, named after `name`
+    fn write_pem(dir: &Path, name: &str, cert: &Certificate, key: &KeyPair) -> (PathBuf, PathBuf) {
+        let (cert_path, key_path) = (
+            dir.join(format!("{name}.pem")),
+            dir.join(format!("{name}.key")),
+        );
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn ca() -> (Certificate, KeyPair) {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        (params.self_signed(&key).unwrap(), key)
+    }
+
+    /// Connects to a server with `server` over loopback and reads one message from it
+    fn handshake(
+        server: Arc<ServerConfig>,
+        client: Arc<ClientConfig>,
+    ) -> Result<String, Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = std::thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            tcp.set_read_timeout(Some(TIMEOUT)).unwrap();
+            // A client that doesn't trust the certificate fails the handshake on this side too
+            if let Ok(mut stream) = accept(server, tcp) {
+                let _ = stream.send(DeleteRequest {
+                    name: "hello".to_string(),
+                });
+            }
+        });
+
+        let tcp = TcpStream::connect(addr)?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        let received = connect(client, "localhost", tcp)
+            .and_then(|mut stream| Ok(stream.recv::<DeleteRequest>()?.name));
+        server.join().unwrap();
+        received
+    }
+
+    #[test]
+    fn handshakes_over_loopback() {
+        let dir = std::env::temp_dir().join(format!("stable-ftp-tls-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Self-signed, which only a pin can trust
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert_path, key_path) = write_pem(&dir, "self-signed", &cert, &key_pair);
+        let server = server_config(&cert_path, &key_path).unwrap();
+
+        let pin = fingerprint(cert.der()).to_uppercase();
+        let pinned = client_config(None, Some(&pin)).unwrap();
+        assert_eq!(handshake(server.clone(), pinned).unwrap(), "hello");
+        let wrong_pin = client_config(None, Some(&"00".repeat(32))).unwrap();
+        assert!(handshake(server, wrong_pin).is_err());
+
+        // Signed by a CA of our own, which a client trusting that CA accepts
+        let (ca, ca_key) = ca();
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        let (cert_path, key_path) = write_pem(&dir, "signed", &cert, &key);
+        let (ca_path, _) = write_pem(&dir, "ca", &ca, &ca_key);
+        let server = server_config(&cert_path, &key_path).unwrap();
+
+        let trusting = client_config(Some(&ca_path), None).unwrap();
+        assert_eq!(handshake(server.clone(), trusting).unwrap(), "hello");
+        let (other_ca, other_key) = ca();
+        let (other_path, _) = write_pem(&dir, "other-ca", &other_ca, &other_key);
+        let other = client_config(Some(&other_path), None).unwrap();
+        assert!(handshake(server, other).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}